@@ -6,21 +6,158 @@ fn main() {
 
     std::fs::write(dest, get_keys()).unwrap();
     println!("cargo::rerun-if-changed=build.rs");
+    println!("cargo::rerun-if-changed={FALLBACK_FILE}");
+    // Watching a missing file would rerun this script on every build
+    let header = event_codes_file_path();
+    if cfg!(feature = "system-headers") || header.exists() {
+        println!("cargo::rerun-if-changed={}", header.display());
+    }
 }
 
+/// `NAME VALUE` pairs
+type Codes = Vec<(String, u32)>;
+
+/// Pregenerated table used when linux-headers are not installed, for docs builders etc
+const FALLBACK_FILE: &str = "input_codes_fallback.txt";
+
 fn get_keys() -> String {
-    let event_codes = get_event_codes().expect("Can get event codes");
+    let (event_codes, aliases) = if cfg!(feature = "system-headers") {
+        get_event_codes().unwrap_or_else(|| {
+            panic!(
+                "Can't read event codes from {}. Install linux-headers or disable the \"system-headers\" feature",
+                event_codes_file_path().display()
+            )
+        })
+    } else {
+        get_event_codes().unwrap_or_else(|| {
+            println!(
+                "cargo::warning=Can't read {}, using the pregenerated key codes instead",
+                event_codes_file_path().display()
+            );
+            get_fallback_event_codes()
+        })
+    };
 
     let mut output = String::new();
 
-    for (key, val) in event_codes {
+    for (key, val) in event_codes.iter().chain(&aliases) {
         let new = format!("pub const {key}: u32 = {val};\n");
         output.push_str(&new);
     }
 
+    let key_codes: Vec<&(String, u32)> = event_codes
+        .iter()
+        .filter(|(key, _)| is_key_name(key))
+        .collect();
+
+    let unique_codes = unique_key_codes(&key_codes);
+
+    output.push_str("\nfn generated_name(code: u32) -> Option<&'static str> {\n");
+    output.push_str("    match code {\n");
+    for (key, val) in &unique_codes {
+        output.push_str(&format!("        {val} => Some(\"{key}\"),\n"));
+    }
+    output.push_str("        _ => None,\n    }\n}\n");
+
+    // The enum is public API, so it comes from the checked in table. Otherwise the variants would
+    // depend on the headers of whoever builds it
+    let (fallback_codes, _) = get_fallback_event_codes();
+    let fallback_key_codes: Vec<&(String, u32)> = fallback_codes
+        .iter()
+        .filter(|(key, _)| is_key_name(key))
+        .collect();
+    output.push_str(&key_enum(&unique_key_codes(&fallback_key_codes)));
+
+    // Aliases like BTN_A can be looked up, but are never the name given for a code
+    let key_aliases = aliases.iter().filter(|(key, _)| is_key_name(key));
+
+    output.push_str("\nfn generated_from_name(name: &str) -> Option<u32> {\n");
+    output.push_str("    match name {\n");
+    for (key, val) in key_codes.iter().copied().chain(key_aliases) {
+        output.push_str(&format!("        \"{key}\" => Some({val}),\n"));
+    }
+    output.push_str("        _ => None,\n    }\n}\n");
+
+    output
+}
+
+/// One name per code. Some codes have several names, like BTN_MOUSE and BTN_LEFT. The header
+/// defines the generic range marker first, so the last name is the most specific one.
+fn unique_key_codes<'a>(key_codes: &[&'a (String, u32)]) -> Vec<&'a (String, u32)> {
+    let mut seen = std::collections::HashSet::new();
+    let mut unique_codes: Vec<&(String, u32)> = key_codes
+        .iter()
+        .rev()
+        .filter(|(_, val)| seen.insert(*val))
+        .copied()
+        .collect();
+    unique_codes.sort_by_key(|(_, val)| *val);
+    unique_codes
+}
+
+/// Generates the `Key` enum, with one variant per code
+fn key_enum(unique_codes: &[&(String, u32)]) -> String {
+    let mut output = String::new();
+
+    output.push_str(
+        "
+/// A keyboard key or button. Only contains `KEY_*` and `BTN_*` codes, unlike the `RawKeyCode`
+/// constants. Codes without a name become `Key::Unknown`. Codes with several names, like
+/// `BTN_SOUTH` and its alias `BTN_A`, have one variant. The other names still work with
+/// `keys::from_name`. Variants come from the table shipped with wlib, not the installed headers,
+/// so they are the same everywhere. Keys compare by code, so `Key::Unknown(30) == Key::KeyA`.
+",
+    );
+    output.push_str("#[non_exhaustive]\n");
+    output.push_str("#[derive(Debug, Clone, Copy)]\n");
+    output.push_str("pub enum Key {\n");
+    for (key, _) in unique_codes {
+        output.push_str(&format!("    {},\n", variant_name(key)));
+    }
+    output.push_str("    Unknown(u32),\n}\n");
+
+    output.push_str("\nimpl From<u32> for Key {\n    fn from(code: u32) -> Self {\n");
+    output.push_str("        match code {\n");
+    for (key, val) in unique_codes {
+        output.push_str(&format!(
+            "            {val} => Key::{},\n",
+            variant_name(key)
+        ));
+    }
+    output.push_str("            _ => Key::Unknown(code),\n        }\n    }\n}\n");
+
+    output.push_str("\nimpl From<Key> for u32 {\n    fn from(key: Key) -> Self {\n");
+    output.push_str("        match key {\n");
+    for (key, val) in unique_codes {
+        output.push_str(&format!(
+            "            Key::{} => {val},\n",
+            variant_name(key)
+        ));
+    }
+    output.push_str("            Key::Unknown(code) => code,\n        }\n    }\n}\n");
+
     output
 }
 
+/// `KEY_LEFTSHIFT` -> `KeyLeftshift`, `BTN_LEFT` -> `BtnLeft`
+fn variant_name(name: &str) -> String {
+    name.split('_')
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_string() + &chars.as_str().to_lowercase(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// Only keyboard keys and buttons, not the other event codes like `EV_SYN` or `ABS_X`
+fn is_key_name(name: &str) -> bool {
+    (name.starts_with("KEY_") || name.starts_with("BTN_"))
+        && !matches!(name, "KEY_MAX" | "KEY_MIN_INTERESTING")
+}
+
 fn event_codes_file_path() -> PathBuf {
     PathBuf::from("/usr/include/linux/input-event-codes.h")
 }
@@ -33,10 +170,13 @@ fn trim_comments(input: &str) -> String {
     while let Some(c) = chars.next() {
         if c == '/' && chars.peek() == Some(&'*') {
             chars.next(); // Consume the *
-            loop {
-                if chars.next() == Some('*') && chars.next() == Some('/') {
+            let mut last = None;
+            // Stops at the end of input too, in case the comment is never closed
+            for next in chars.by_ref() {
+                if last == Some('*') && next == '/' {
                     break;
                 }
+                last = Some(next);
             }
         } else {
             out.push(c);
@@ -61,49 +201,101 @@ fn trim_empty_lines(input: &str) -> Vec<String> {
     out
 }
 
+/// Picks the `#define NAME VALUE` lines out of the header. Anything else, including defines whose
+/// value isn't a plain number, is skipped. Returns `None` if nothing could be found.
 fn to_key_val(input: &[String]) -> Option<Vec<(String, u32)>> {
     let mut out = Vec::new();
 
-    let mut lines = input.iter();
-
-    while let Some(line) = lines.next() {
-        if line.starts_with("#ifndef") || line.starts_with("#endif") {
-            lines.next(); // Skip next line
+    for line in input {
+        let Some(define) = line.strip_prefix("#define") else {
             continue;
-        }
+        };
 
-        let mut s = line.trim_start_matches("#define").split_whitespace();
-        // let key = s.next()?.to_string();
-        // let val_str = s.next()?;
-        let key = s.next().unwrap().to_string();
-        let val_str = s.next().unwrap();
+        let mut s = define.split_whitespace();
+        let (Some(key), Some(val_str)) = (s.next(), s.next()) else {
+            // Include guards etc
+            continue;
+        };
 
         // Dont include weird things that reference other keys. Not needed
         if val_str.starts_with('(') {
             continue;
         }
 
-        let radix = if val_str.starts_with("0x") { 16 } else { 10 };
-
-        let mut trimed = val_str.trim_start_matches("0x").trim_start_matches("0");
-        if trimed.is_empty() {
-            trimed = "0";
-        }
-
-        let Some(val) = u32::from_str_radix(trimed, radix).ok() else {
+        let Some(val) = parse_number(val_str) else {
             continue;
         };
 
-        out.push((key, val));
+        out.push((key.to_string(), val));
+    }
+
+    if out.is_empty() { None } else { Some(out) }
+}
+
+fn parse_number(val_str: &str) -> Option<u32> {
+    let radix = if val_str.starts_with("0x") { 16 } else { 10 };
+
+    let mut trimed = val_str.trim_start_matches("0x").trim_start_matches("0");
+    if trimed.is_empty() {
+        trimed = "0";
     }
 
-    Some(out)
+    u32::from_str_radix(trimed, radix).ok()
+}
+
+/// Picks the `#define ALIAS NAME` lines out of the header, like `#define BTN_A BTN_SOUTH`, and
+/// gives them the value of `NAME`
+fn to_aliases(input: &[String], codes: &[(String, u32)]) -> Vec<(String, u32)> {
+    let pairs = input.iter().filter_map(|line| {
+        let mut s = line.strip_prefix("#define")?.split_whitespace();
+        Some((s.next()?, s.next()?))
+    });
+    resolve_aliases(pairs, codes)
 }
 
-fn get_event_codes() -> Option<Vec<(String, u32)>> {
-    to_key_val(&trim_empty_lines(&trim_comments(
+fn resolve_aliases<'a>(
+    pairs: impl Iterator<Item = (&'a str, &'a str)>,
+    codes: &[(String, u32)],
+) -> Vec<(String, u32)> {
+    pairs
+        .filter_map(|(alias, name)| {
+            let (_, val) = codes.iter().find(|(key, _)| key == name)?;
+            Some((alias.to_string(), *val))
+        })
+        .collect()
+}
+
+fn get_event_codes() -> Option<(Codes, Codes)> {
+    let lines = trim_empty_lines(&trim_comments(
         &std::fs::read_to_string(event_codes_file_path()).ok()?,
-    )))
+    ));
+    let codes = to_key_val(&lines)?;
+    let aliases = to_aliases(&lines, &codes);
+    Some((codes, aliases))
+}
+
+fn get_fallback_event_codes() -> (Codes, Codes) {
+    parse_fallback(&std::fs::read_to_string(FALLBACK_FILE).expect("Can read fallback key codes"))
+}
+
+/// The fallback file has one `NAME VALUE` pair per line, with `#` comments. Aliases are written as
+/// `ALIAS NAME`
+fn parse_fallback(input: &str) -> (Codes, Codes) {
+    let pairs: Vec<(&str, &str)> = input
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let (key, val) = line.split_once(' ')?;
+            Some((key, val.trim()))
+        })
+        .collect();
+
+    let codes: Vec<(String, u32)> = pairs
+        .iter()
+        .filter_map(|(key, val)| Some((key.to_string(), val.parse().ok()?)))
+        .collect();
+    let aliases = resolve_aliases(pairs.iter().copied(), &codes);
+    (codes, aliases)
 }
 
 #[cfg(test)]
@@ -120,6 +312,65 @@ mod tests {
         to_key_val(trimed).unwrap();
     }
 
+    #[test]
+    fn test_fallback_matches_header() {
+        // Only meaningful where linux-headers are installed
+        let Some(from_header) = get_event_codes() else {
+            return;
+        };
+
+        let fallback = get_fallback_event_codes();
+        for code in fallback.0.iter().chain(&fallback.1) {
+            assert!(
+                from_header.0.contains(code) || from_header.1.contains(code),
+                "{code:?} not in header"
+            );
+        }
+        // Otherwise the alias constants only exist where the header is installed
+        for alias in &from_header.1 {
+            assert!(
+                fallback.1.contains(alias),
+                "{alias:?} missing from fallback"
+            );
+        }
+    }
+
+    #[test]
+    fn test_unexpected_lines() {
+        let lined = trim_empty_lines(&trim_comments(
+            r#"#ifndef _UAPI_INPUT_EVENT_CODES_H
+#define _UAPI_INPUT_EVENT_CODES_H
+#include <linux/types.h>
+#define KEY_ESC			1
+#define
+#define KEY_WEIRD		0xZZ
+#define KEY_HANGUEL		KEY_HANGEUL
+#endif
+/* never closed"#,
+        ));
+
+        let codes = vec![("KEY_ESC".to_string(), 1)];
+        assert_eq!(to_key_val(&lined), Some(codes.clone()));
+        assert_eq!(to_key_val(&[]), None);
+        // KEY_HANGEUL is not defined in this snippet
+        assert_eq!(to_aliases(&lined, &codes), vec![]);
+
+        assert_eq!(
+            parse_fallback("# comment\nKEY_ESC 1\nBTN_LEFT 272\nnonsense\nBTN_X BTN_LEFT\n"),
+            (
+                vec![("KEY_ESC".to_string(), 1), ("BTN_LEFT".to_string(), 272)],
+                vec![("BTN_X".to_string(), 272)]
+            )
+        );
+    }
+
+    #[test]
+    fn test_variant_name() {
+        assert_eq!(variant_name("KEY_LEFTSHIFT"), "KeyLeftshift");
+        assert_eq!(variant_name("KEY_102ND"), "Key102nd");
+        assert_eq!(variant_name("BTN_TRIGGER_HAPPY1"), "BtnTriggerHappy1");
+    }
+
     #[test]
     fn test_all_manual() {
         // God this is awful to look at
@@ -0,0 +1,442 @@
+//! Reusing shared memory buffers between frames, so drawing doesn't allocate
+
+use std::collections::VecDeque;
+
+use smithay_client_toolkit::{
+    reexports::client::protocol::wl_shm,
+    shm::{
+        Shm,
+        slot::{Buffer, SlotPool},
+    },
+};
+
+use crate::{Rect, WindowSize};
+
+/// Don't bother shrinking pools smaller than this
+const MIN_TRIM_SIZE: usize = 4 << 20;
+
+/// How many frames of damage `RetainedFrame` remembers. Buffers older than this are copied whole
+const DAMAGE_HISTORY: usize = 4;
+
+/// How many buffers each window draws into. The compositor reads a buffer until it sends a
+/// release, so a window needs at least one more buffer than the compositor is holding
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Buffering {
+    /// One buffer for the compositor to show, one to draw into. Uses the least memory, but
+    /// compositors that hold on to a buffer after the next one arrives force an allocation
+    #[default]
+    Double,
+    /// A spare buffer for compositors that release buffers late, so drawing never waits or
+    /// allocates. Costs one more window's worth of memory
+    Triple,
+}
+
+impl Buffering {
+    pub(crate) fn buffer_count(self) -> usize {
+        match self {
+            Buffering::Double => 2,
+            Buffering::Triple => 3,
+        }
+    }
+}
+
+/// Memory used by the buffers of all windows. Useful for debugging memory use
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Bytes of shared memory allocated
+    pub pool_size: usize,
+    /// Bytes of the pool used by buffers
+    pub used: usize,
+    /// Buffers across all windows
+    pub buffers: usize,
+    /// Buffers the compositor is still reading from
+    pub busy_buffers: usize,
+    /// Buffers allocated since the start. Only goes up when a window changes size
+    pub allocations: u64,
+    /// Times the pool was shrunk after windows got smaller or closed
+    pub shrinks: u64,
+}
+
+/// The shape of a buffer. Buffers are only reused for frames with the same layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BufferLayout {
+    pub width: i32,
+    pub height: i32,
+    pub stride: i32,
+    pub format: wl_shm::Format,
+}
+
+/// The shared memory every window's buffers are allocated from
+pub(crate) struct BufferPool {
+    pool: SlotPool,
+    /// Counts up every time `pool` is replaced, so buffers know which pool they are in
+    generation: u64,
+    /// Pools replaced by `trim`, with their generation. Kept until every buffer in them has moved
+    /// to `pool`
+    retired: Vec<(u64, SlotPool)>,
+    allocations: u64,
+    shrinks: u64,
+}
+
+impl BufferPool {
+    pub fn new(len: usize, shm: &Shm) -> Self {
+        Self {
+            pool: SlotPool::new(len, shm).expect("Failed to create pool"),
+            generation: 0,
+            retired: Vec::new(),
+            allocations: 0,
+            shrinks: 0,
+        }
+    }
+
+    fn create_buffer(&mut self, layout: BufferLayout) -> RingEntry {
+        let (buffer, _) = self
+            .pool
+            .create_buffer(layout.width, layout.height, layout.stride, layout.format)
+            .expect("create buffer");
+        self.allocations += 1;
+        RingEntry {
+            buffer,
+            frame: None,
+            generation: self.generation,
+        }
+    }
+
+    pub fn stats<'a>(&self, rings: impl Iterator<Item = &'a BufferRing>) -> PoolStats {
+        let retired_size: usize = self.retired.iter().map(|(_, pool)| pool.len()).sum();
+        let mut stats = PoolStats {
+            pool_size: self.pool.len() + retired_size,
+            allocations: self.allocations,
+            shrinks: self.shrinks,
+            ..PoolStats::default()
+        };
+        for entry in rings.flat_map(|ring| &ring.buffers) {
+            let slot = entry.buffer.slot();
+            stats.used += slot.len();
+            stats.buffers += 1;
+            stats.busy_buffers += slot.has_active_buffers() as usize;
+        }
+        stats
+    }
+
+    /// The pool only ever grows, so after windows get smaller or close, most of it can sit unused.
+    /// Replaces it with a smaller one when that happens. Buffers keep their contents, and move
+    /// to the new pool as they are next drawn into. Call after frames are committed
+    pub fn trim(&mut self, shm: &Shm, rings: &[&BufferRing]) {
+        let in_use = |generation: u64| {
+            rings
+                .iter()
+                .flat_map(|ring| &ring.buffers)
+                .any(|entry| entry.generation == generation)
+        };
+        self.retired.retain(|(generation, _)| in_use(*generation));
+
+        // Nothing has been drawn yet, so the pool still has the size it was created with. Or the
+        // last trim is still moving buffers over
+        if rings.iter().all(|ring| ring.layout.is_none()) || !self.retired.is_empty() {
+            return;
+        }
+
+        let used = self.stats(rings.iter().copied()).used;
+        if !should_trim(self.pool.len(), used) {
+            return;
+        }
+
+        let pool = SlotPool::new(used.max(4096), shm).expect("Failed to create pool");
+        let old_pool = std::mem::replace(&mut self.pool, pool);
+        self.retired.push((self.generation, old_pool));
+        self.generation += 1;
+        self.shrinks += 1;
+    }
+}
+
+fn should_trim(pool_size: usize, used: usize) -> bool {
+    pool_size > MIN_TRIM_SIZE && pool_size > used * 4
+}
+
+/// The buffers one surface draws into. The compositor reads from a buffer until it releases it,
+/// so while it is busy the next frame goes into another one
+#[derive(Default)]
+pub(crate) struct BufferRing {
+    layout: Option<BufferLayout>,
+    buffers: Vec<RingEntry>,
+    /// Counts up with every frame drawn
+    frame: u64,
+}
+
+struct RingEntry {
+    buffer: Buffer,
+    /// The frame this buffer was last drawn for. `None` if it was never drawn into
+    frame: Option<u64>,
+    /// The `BufferPool::generation` of the pool the buffer is in
+    generation: u64,
+}
+
+impl BufferRing {
+    /// A buffer the compositor has released, its pixels, and how many frames ago it was last
+    /// drawn into. Reuses an old buffer if there is one with the same layout, otherwise allocates
+    /// until there are as many as `buffering` allows
+    pub fn acquire<'a>(
+        &'a mut self,
+        pool: &'a mut BufferPool,
+        layout: BufferLayout,
+        buffering: Buffering,
+    ) -> (&'a Buffer, &'a mut [u8], Option<u64>) {
+        if self.layout != Some(layout) {
+            self.clear();
+            self.layout = Some(layout);
+        }
+        self.frame += 1;
+
+        // The most recently drawn free buffer has the least to catch up on
+        let free = self
+            .buffers
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| !entry.buffer.slot().has_active_buffers())
+            .max_by_key(|(_, entry)| entry.frame)
+            .map(|(index, _)| index);
+        let index = match free {
+            Some(index) => {
+                if self.buffers[index].generation != pool.generation {
+                    self.buffers[index] = move_to_current_pool(pool, &self.buffers[index], layout);
+                }
+                index
+            }
+            None => {
+                let entry = pool.create_buffer(layout);
+
+                if self.buffers.len() < buffering.buffer_count() {
+                    self.buffers.push(entry);
+                    self.buffers.len() - 1
+                } else {
+                    // The compositor is holding on to every buffer. The oldest one is destroyed
+                    // once it is released
+                    let (oldest, _) = self
+                        .buffers
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, entry)| entry.frame)
+                        .expect("ring is not empty");
+                    self.buffers[oldest] = entry;
+                    oldest
+                }
+            }
+        };
+
+        let entry = &mut self.buffers[index];
+        let age = entry.frame.map(|frame| self.frame - frame);
+        entry.frame = Some(self.frame);
+        let canvas = pool.pool.canvas(&entry.buffer).expect("buffer is free");
+        (&entry.buffer, canvas, age)
+    }
+
+    /// Drops every buffer, so the next frame allocates
+    fn clear(&mut self) {
+        self.layout = None;
+        self.buffers.clear();
+    }
+}
+
+/// Copies a free buffer from a retired pool into a new one in the current pool
+fn move_to_current_pool(
+    pool: &mut BufferPool,
+    entry: &RingEntry,
+    layout: BufferLayout,
+) -> RingEntry {
+    let mut moved = pool.create_buffer(layout);
+    moved.frame = entry.frame;
+
+    let old_pool = pool
+        .retired
+        .iter_mut()
+        .find(|(generation, _)| *generation == entry.generation)
+        .map(|(_, old_pool)| old_pool);
+    if let Some(old_pool) = old_pool
+        && let Some(old_canvas) = entry.buffer.canvas(old_pool)
+        && let Some(canvas) = moved.buffer.canvas(&mut pool.pool)
+    {
+        canvas.copy_from_slice(old_canvas);
+    } else {
+        // Without the old contents the buffer counts as never drawn into
+        moved.frame = None;
+    }
+    moved
+}
+
+/// A copy of the last frame, so `WLibSettings::with_retained` can hand the app a buffer that
+/// already holds it. Only the parts damaged since a buffer was last drawn into are copied over
+#[derive(Default)]
+pub(crate) struct RetainedFrame {
+    pixels: Vec<u8>,
+    size: Option<WindowSize>,
+    /// What each of the last few frames changed, newest last. `None` for the whole frame
+    damage: VecDeque<Option<Vec<Rect>>>,
+}
+
+impl RetainedFrame {
+    /// Brings `canvas` up to date with the last frame. `age` is how many frames ago it was last
+    /// drawn into, `None` if never. Starts out transparent when there is no last frame, like after
+    /// a resize
+    pub fn restore(
+        &self,
+        canvas: &mut [u8],
+        size: WindowSize,
+        bytes_per_pixel: usize,
+        age: Option<u64>,
+    ) {
+        if self.size != Some(size) {
+            canvas.fill(0);
+            return;
+        }
+
+        let Some(age) = age.filter(|age| *age as usize <= self.damage.len() + 1) else {
+            canvas.copy_from_slice(&self.pixels);
+            return;
+        };
+        // Frames drawn since this buffer last was
+        let missed = self
+            .damage
+            .iter()
+            .skip(self.damage.len() + 1 - age as usize);
+        for damage in missed {
+            match damage {
+                Some(rects) => {
+                    for rect in rects {
+                        copy_rect(&self.pixels, canvas, *rect, size, bytes_per_pixel);
+                    }
+                }
+                None => {
+                    canvas.copy_from_slice(&self.pixels);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Keeps the parts of `canvas` the frame just drawn changed. `damage` is `None` if all of it
+    /// did
+    pub fn save(
+        &mut self,
+        canvas: &[u8],
+        size: WindowSize,
+        bytes_per_pixel: usize,
+        damage: Option<&[Rect]>,
+    ) {
+        let damage = damage.filter(|_| self.size == Some(size));
+        match damage {
+            Some(rects) => {
+                for rect in rects {
+                    copy_rect(canvas, &mut self.pixels, *rect, size, bytes_per_pixel);
+                }
+            }
+            None => {
+                self.pixels.clear();
+                self.pixels.extend_from_slice(canvas);
+                self.size = Some(size);
+            }
+        }
+
+        if self.damage.len() == DAMAGE_HISTORY {
+            self.damage.pop_front();
+        }
+        self.damage.push_back(damage.map(<[Rect]>::to_vec));
+    }
+}
+
+/// Copies the `rect` part of `source` to `target`, both `size` images
+fn copy_rect(
+    source: &[u8],
+    target: &mut [u8],
+    rect: Rect,
+    size: WindowSize,
+    bytes_per_pixel: usize,
+) {
+    let left = rect.x.clamp(0, size.width as i32) as usize;
+    let top = rect.y.clamp(0, size.height as i32) as usize;
+    let right = (rect.x as i64 + rect.width as i64).clamp(0, size.width as i64) as usize;
+    let bottom = (rect.y as i64 + rect.height as i64).clamp(0, size.height as i64) as usize;
+    if left >= right {
+        return;
+    }
+
+    let stride = size.width as usize * bytes_per_pixel;
+    for y in top..bottom {
+        let row = y * stride;
+        let range = row + left * bytes_per_pixel..row + right * bytes_per_pixel;
+        target[range.clone()].copy_from_slice(&source[range]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_trim() {
+        // Small pools are left alone
+        assert!(!should_trim(MIN_TRIM_SIZE, 0));
+        // Mostly used
+        assert!(!should_trim(32 << 20, 16 << 20));
+        // A window went from fullscreen to small
+        assert!(should_trim(32 << 20, 1 << 20));
+        // Every window closed
+        assert!(should_trim(32 << 20, 0));
+    }
+
+    #[test]
+    fn test_retained_frame() {
+        let size = WindowSize {
+            width: 2,
+            height: 2,
+        };
+        let left_column = Rect {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 2,
+        };
+        let bottom_right = Rect {
+            x: 1,
+            y: 1,
+            width: 5,
+            height: 5,
+        };
+        let mut retained = RetainedFrame::default();
+
+        // Frame 1 into buffer A, full
+        let mut a = vec![1; 4];
+        retained.restore(&mut a, size, 1, None);
+        assert_eq!(a, [0; 4]);
+        a.fill(1);
+        retained.save(&a, size, 1, None);
+
+        // Frame 2 into new buffer B, which gets a full copy
+        let mut b = vec![9; 4];
+        retained.restore(&mut b, size, 1, None);
+        assert_eq!(b, [1; 4]);
+        b[0] = 2;
+        b[2] = 2;
+        retained.save(&b, size, 1, Some(&[left_column]));
+
+        // Frame 3 back into A, which missed frame 2
+        retained.restore(&mut a, size, 1, Some(2));
+        assert_eq!(a, [2, 1, 2, 1]);
+        a[3] = 3;
+        retained.save(&a, size, 1, Some(&[bottom_right]));
+
+        // Frame 4 into B, which missed frame 3. Frame 2's damage is already in it
+        b[0] = 7;
+        retained.restore(&mut b, size, 1, Some(2));
+        assert_eq!(b, [7, 1, 2, 3]);
+
+        // Resizing starts over
+        let mut c = vec![5; 9];
+        let bigger = WindowSize {
+            width: 3,
+            height: 3,
+        };
+        retained.restore(&mut c, bigger, 1, Some(1));
+        assert_eq!(c, [0; 9]);
+    }
+}
@@ -0,0 +1,204 @@
+//! Colors and alpha blending.
+//!
+//! Wayland expects the `Argb8888` and `Abgr8888` pixel formats to hold premultiplied alpha: the
+//! red, green and blue channels are already multiplied by alpha, so none of them can be larger
+//! than it. A half transparent white is `(128, 128, 128, 128)`, not `(255, 255, 255, 128)`.
+//! Colors breaking that rule look different on every compositor.
+//!
+//! Use `Color` for colors as you would pick them, then `Color::premultiply` before writing them to
+//! the buffer.
+//! # Example
+//! ```rust
+//! use wlib::color::Color;
+//!
+//! fn draw(buffer: &mut [u8]) {
+//!     let background = Color::rgb(30, 30, 60).premultiply();
+//!     let glass = Color::rgba(255, 255, 255, 64).premultiply();
+//!
+//!     let pixel = glass.over(background).to_argb8888();
+//!     for chunk in buffer.chunks_exact_mut(4) {
+//!         chunk.copy_from_slice(&pixel);
+//!     }
+//! }
+//! ```
+
+/// A color with straight alpha, as you would pick in an image editor. Convert it with
+/// `premultiply` before drawing it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+/// A color with premultiplied alpha, like the compositor expects. No channel should be larger
+/// than `a`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Premultiplied {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+/// `value * alpha / 255`, rounded
+fn multiply(value: u8, alpha: u8) -> u8 {
+    ((value as u32 * alpha as u32 + 127) / 255) as u8
+}
+
+impl Color {
+    pub const fn rgba(r: u8, g: u8, b: u8, a: u8) -> Self {
+        Self { r, g, b, a }
+    }
+
+    /// A fully opaque color
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Self::rgba(r, g, b, 0xFF)
+    }
+
+    pub fn premultiply(self) -> Premultiplied {
+        Premultiplied {
+            r: multiply(self.r, self.a),
+            g: multiply(self.g, self.a),
+            b: multiply(self.b, self.a),
+            a: self.a,
+        }
+    }
+}
+
+impl Premultiplied {
+    /// Back to straight alpha. Fully transparent colors have no color left, so become black
+    pub fn unpremultiply(self) -> Color {
+        let divide = |value: u8| {
+            if self.a == 0 {
+                0
+            } else {
+                ((value as u32 * 255 + self.a as u32 / 2) / self.a as u32).min(255) as u8
+            }
+        };
+        Color {
+            r: divide(self.r),
+            g: divide(self.g),
+            b: divide(self.b),
+            a: self.a,
+        }
+    }
+
+    /// Draws `self` on top of `below`
+    pub fn over(self, below: Premultiplied) -> Premultiplied {
+        let remaining = 255 - self.a;
+        Premultiplied {
+            r: self.r.saturating_add(multiply(below.r, remaining)),
+            g: self.g.saturating_add(multiply(below.g, remaining)),
+            b: self.b.saturating_add(multiply(below.b, remaining)),
+            a: self.a.saturating_add(multiply(below.a, remaining)),
+        }
+    }
+
+    /// Does no channel exceed alpha?
+    pub fn is_valid(self) -> bool {
+        self.r <= self.a && self.g <= self.a && self.b <= self.a
+    }
+
+    /// The bytes of a pixel in `PixelFormat::Argb8888`
+    pub fn to_argb8888(self) -> [u8; 4] {
+        [self.b, self.g, self.r, self.a]
+    }
+
+    pub fn from_argb8888(bytes: [u8; 4]) -> Self {
+        let [b, g, r, a] = bytes;
+        Self { r, g, b, a }
+    }
+
+    /// The bytes of a pixel in `PixelFormat::Abgr8888`
+    pub fn to_abgr8888(self) -> [u8; 4] {
+        [self.r, self.g, self.b, self.a]
+    }
+
+    pub fn from_abgr8888(bytes: [u8; 4]) -> Self {
+        let [r, g, b, a] = bytes;
+        Self { r, g, b, a }
+    }
+}
+
+/// Premultiplies a whole `Argb8888` or `Abgr8888` buffer that was drawn with straight alpha
+pub fn premultiply_buffer(buffer: &mut [u8]) {
+    for pixel in buffer.chunks_exact_mut(4) {
+        let alpha = pixel[3];
+        for channel in &mut pixel[..3] {
+            *channel = multiply(*channel, alpha);
+        }
+    }
+}
+
+/// The index of the first pixel in an `Argb8888` or `Abgr8888` buffer with a color channel larger
+/// than its alpha, if there is one
+pub fn find_invalid_pixel(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .chunks_exact(4)
+        .position(|pixel| pixel[..3].iter().any(|channel| *channel > pixel[3]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_premultiply() {
+        let white = Color::rgba(255, 255, 255, 128).premultiply();
+        assert_eq!(
+            white,
+            Premultiplied {
+                r: 128,
+                g: 128,
+                b: 128,
+                a: 128
+            }
+        );
+        assert_eq!(white.unpremultiply(), Color::rgba(255, 255, 255, 128));
+
+        let orange = Color::rgba(255, 128, 0, 200);
+        assert_eq!(orange.premultiply().unpremultiply(), orange);
+        assert!(orange.premultiply().is_valid());
+
+        assert_eq!(
+            Color::rgba(10, 20, 30, 0).premultiply().unpremultiply(),
+            Color::default()
+        );
+
+        let mut buffer = [255, 128, 0, 128, 10, 20, 30, 255];
+        premultiply_buffer(&mut buffer);
+        assert_eq!(buffer, [128, 64, 0, 128, 10, 20, 30, 255]);
+    }
+
+    #[test]
+    fn test_over() {
+        let red = Color::rgb(255, 0, 0).premultiply();
+        let blue = Color::rgba(0, 0, 255, 128).premultiply();
+        let transparent = Premultiplied::default();
+
+        assert_eq!(
+            blue.over(red),
+            Premultiplied {
+                r: 127,
+                g: 0,
+                b: 128,
+                a: 255
+            }
+        );
+        assert_eq!(red.over(blue), red);
+        assert_eq!(transparent.over(blue), blue);
+        assert_eq!(blue.over(transparent), blue);
+    }
+
+    #[test]
+    fn test_find_invalid_pixel() {
+        let valid = Color::rgba(255, 100, 0, 100).premultiply().to_argb8888();
+        let invalid = [255, 100, 0, 100];
+
+        assert_eq!(find_invalid_pixel(&[valid, valid].concat()), None);
+        assert_eq!(find_invalid_pixel(&[valid, invalid].concat()), Some(1));
+        assert_eq!(find_invalid_pixel(&[0, 0, 0, 0]), None);
+    }
+}
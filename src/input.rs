@@ -0,0 +1,378 @@
+//! Helpers for querying keyboard and mouse input, and mapping it to configurable actions
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::keys::{Key, KeySym};
+use crate::{Context, MouseButton};
+
+/// Anything that can be held down. Used by `Context::is_down`, `Context::was_pressed` and
+/// `Context::was_released` so they can be asked about keys and mouse buttons alike.
+///
+/// You usually dont need to build this yourself, since `Key`, `KeySym` and `MouseButton` all
+/// convert into it.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub enum Input {
+    /// A physical key, irrespective of layout. See `keys::Key`
+    Key(Key),
+    /// The symbol a key produced, so it depends on layout and modifiers. See `keys::KeySym`
+    Sym(KeySym),
+    /// A mouse button
+    Mouse(MouseButton),
+}
+
+impl From<Key> for Input {
+    /// Mouse buttons like `Key::BtnLeft` become `Input::Mouse` so they match mouse presses
+    fn from(value: Key) -> Self {
+        match MouseButton::try_from(u32::from(value)) {
+            Ok(button) => Input::Mouse(button),
+            Err(()) => Input::Key(value),
+        }
+    }
+}
+
+impl From<KeySym> for Input {
+    fn from(value: KeySym) -> Self {
+        Input::Sym(value)
+    }
+}
+
+impl From<MouseButton> for Input {
+    fn from(value: MouseButton) -> Self {
+        Input::Mouse(value)
+    }
+}
+
+impl std::fmt::Display for Input {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Input::Key(key) => write!(f, "{key}"),
+            Input::Mouse(button) => write!(f, "{}", Key::from(u32::from(button.clone()))),
+            Input::Sym(sym) => write!(f, "SYM_{}", xkbcommon::xkb::keysym_get_name(*sym)),
+        }
+    }
+}
+
+impl std::str::FromStr for Input {
+    type Err = String;
+
+    /// Parses the names written by `Display`. Accepts key names from
+    /// "/usr/include/linux/input-event-codes.h" like `KEY_SPACE` or `BTN_LEFT`, xkb key symbol
+    /// names prefixed with `SYM_` like `SYM_Return`, or a raw key code number.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(sym_name) = s.strip_prefix("SYM_") {
+            let sym = xkbcommon::xkb::keysym_from_name(sym_name, xkbcommon::xkb::KEYSYM_NO_FLAGS);
+            if sym == KeySym::NoSymbol {
+                return Err(format!("unknown key symbol \"{sym_name}\""));
+            }
+            return Ok(Input::Sym(sym));
+        }
+
+        let code = match crate::keys::from_name(s) {
+            Some(code) => code,
+            None => s.parse().map_err(|_| format!("unknown key \"{s}\""))?,
+        };
+
+        Ok(Input::from(Key::from(code)))
+    }
+}
+
+/// One way of triggering an action. An input plus the modifiers that must be held with it.
+///
+/// Modifiers that are not required are ignored, so a binding to `KEY_W` still triggers while shift
+/// is held, but a binding to `CTRL+KEY_S` does not trigger without control.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binding {
+    pub input: Input,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+    pub logo: bool,
+}
+
+impl Binding {
+    pub fn new(input: impl Into<Input>) -> Self {
+        Self {
+            input: input.into(),
+            ctrl: false,
+            alt: false,
+            shift: false,
+            logo: false,
+        }
+    }
+
+    pub fn with_ctrl(mut self) -> Self {
+        self.ctrl = true;
+        self
+    }
+
+    pub fn with_alt(mut self) -> Self {
+        self.alt = true;
+        self
+    }
+
+    pub fn with_shift(mut self) -> Self {
+        self.shift = true;
+        self
+    }
+
+    /// The "windows" or "super" key
+    pub fn with_logo(mut self) -> Self {
+        self.logo = true;
+        self
+    }
+
+    fn modifiers_held(&self, context: &Context) -> bool {
+        let held = &context.modifiers;
+        (!self.ctrl || held.ctrl)
+            && (!self.alt || held.alt)
+            && (!self.shift || held.shift)
+            && (!self.logo || held.logo)
+    }
+}
+
+impl std::fmt::Display for Binding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (held, name) in [
+            (self.ctrl, "CTRL"),
+            (self.alt, "ALT"),
+            (self.shift, "SHIFT"),
+            (self.logo, "LOGO"),
+        ] {
+            if held {
+                write!(f, "{name}+")?;
+            }
+        }
+        write!(f, "{}", self.input)
+    }
+}
+
+impl std::str::FromStr for Binding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts: Vec<&str> = s.split('+').map(str::trim).collect();
+        let input = parts.pop().unwrap_or_default().parse::<Input>()?;
+
+        let mut binding = Binding::new(input);
+        for modifier in parts {
+            binding = match modifier {
+                "CTRL" => binding.with_ctrl(),
+                "ALT" => binding.with_alt(),
+                "SHIFT" => binding.with_shift(),
+                "LOGO" | "SUPER" => binding.with_logo(),
+                _ => return Err(format!("unknown modifier \"{modifier}\"")),
+            };
+        }
+
+        Ok(binding)
+    }
+}
+
+/// Maps named actions like `"jump"` to the inputs that trigger them, so controls can be rebound
+/// instead of hard coding keys in `update`.
+///
+/// Can be saved to and loaded from a simple text format, one action per line:
+/// ```text
+/// # Comments start with a hash
+/// jump = KEY_SPACE, BTN_LEFT
+/// save = CTRL+KEY_S
+/// confirm = SYM_Return
+/// ```
+/// # Example
+/// ```rust
+/// use wlib::input::{ActionMap, Binding};
+///
+/// let actions = ActionMap::new()
+///     .with_binding("jump", Binding::new(wlib::keys::Key::KeySpace))
+///     .with_binding("save", Binding::new(wlib::keys::Key::KeyS).with_ctrl());
+///
+/// assert_eq!(actions.to_string(), "jump = KEY_SPACE\nsave = CTRL+KEY_S\n");
+///
+/// fn update(actions: &ActionMap, context: &wlib::Context) {
+///     if actions.just_triggered("jump", context) {
+///         // Jump
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ActionMap {
+    actions: BTreeMap<String, Vec<Binding>>,
+}
+
+impl ActionMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_binding(mut self, action: &str, binding: Binding) -> Self {
+        self.bind(action, binding);
+        self
+    }
+
+    /// Adds another way to trigger `action`
+    pub fn bind(&mut self, action: &str, binding: Binding) {
+        let bindings = self.actions.entry(action.to_string()).or_default();
+        if !bindings.contains(&binding) {
+            bindings.push(binding);
+        }
+    }
+
+    /// Removes every binding for `action`
+    pub fn unbind(&mut self, action: &str) {
+        self.actions.remove(action);
+    }
+
+    pub fn bindings(&self, action: &str) -> &[Binding] {
+        self.actions.get(action).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    pub fn actions(&self) -> impl Iterator<Item = &str> {
+        self.actions.keys().map(String::as_str)
+    }
+
+    /// Is any input bound to `action` currently held down?
+    pub fn is_active(&self, action: &str, context: &Context) -> bool {
+        self.bindings(action)
+            .iter()
+            .any(|b| context.is_down(b.input.clone()) && b.modifiers_held(context))
+    }
+
+    /// Did any input bound to `action` go down since the last frame?
+    pub fn just_triggered(&self, action: &str, context: &Context) -> bool {
+        self.bindings(action)
+            .iter()
+            .any(|b| context.was_pressed(b.input.clone()) && b.modifiers_held(context))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        std::fs::read_to_string(path)?
+            .parse()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_string())
+    }
+}
+
+/// A problem with a line in the `ActionMap` text format
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseActionMapError {
+    /// Line number, starting from 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for ParseActionMapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseActionMapError {}
+
+impl std::str::FromStr for ActionMap {
+    type Err = ParseActionMapError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut map = ActionMap::new();
+
+        for (index, line) in s.lines().enumerate() {
+            let error = |message: String| ParseActionMapError {
+                line: index + 1,
+                message,
+            };
+
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let Some((action, bindings)) = line.split_once('=') else {
+                return Err(error("expected \"action = bindings\"".to_string()));
+            };
+
+            let action = action.trim();
+            if action.is_empty() {
+                return Err(error("missing action name".to_string()));
+            }
+
+            for binding in bindings.split(',').map(str::trim) {
+                if binding.is_empty() {
+                    continue;
+                }
+                map.bind(action, binding.parse().map_err(error)?);
+            }
+        }
+
+        Ok(map)
+    }
+}
+
+impl std::fmt::Display for ActionMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (action, bindings) in &self.actions {
+            let bindings: Vec<String> = bindings.iter().map(Binding::to_string).collect();
+            writeln!(f, "{action} = {}", bindings.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_action_map_round_trip() {
+        let text = "\
+# Movement
+jump = KEY_SPACE, BTN_LEFT
+save = CTRL+SHIFT+KEY_S # Save as
+confirm = SYM_Return
+";
+        let map: ActionMap = text.parse().unwrap();
+
+        assert_eq!(
+            map.bindings("jump"),
+            &[
+                Binding::new(Key::KeySpace),
+                Binding::new(MouseButton::BtnLeft)
+            ]
+        );
+        assert_eq!(
+            map.bindings("save"),
+            &[Binding::new(Key::KeyS).with_ctrl().with_shift()]
+        );
+        assert_eq!(map.bindings("confirm"), &[Binding::new(KeySym::Return)]);
+
+        assert_eq!(map.to_string().parse::<ActionMap>().unwrap(), map);
+    }
+
+    #[test]
+    fn test_input_names() {
+        // BTN_LEFT shares its code with BTN_MOUSE, but is the name written out
+        let left = Input::Mouse(MouseButton::BtnLeft);
+        assert_eq!(left.to_string(), "BTN_LEFT");
+        assert_eq!("BTN_LEFT".parse::<Input>(), Ok(left.clone()));
+        assert_eq!("BTN_MOUSE".parse::<Input>(), Ok(left));
+
+        let space = Input::Key(Key::KeySpace);
+        assert_eq!(space.to_string().parse::<Input>(), Ok(space));
+    }
+
+    #[test]
+    fn test_action_map_errors() {
+        assert_eq!(
+            "jump = KEY_SPACE\nrun KEY_LEFTSHIFT".parse::<ActionMap>(),
+            Err(ParseActionMapError {
+                line: 2,
+                message: "expected \"action = bindings\"".to_string()
+            })
+        );
+        assert!("jump = KEY_NOT_A_KEY".parse::<ActionMap>().is_err());
+        assert!("jump = HYPER+KEY_SPACE".parse::<ActionMap>().is_err());
+        assert!("jump = SYM_NotASym".parse::<ActionMap>().is_err());
+    }
+}
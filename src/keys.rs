@@ -1,10 +1,15 @@
 //! Useful keyboard related types
 //! Contains all physical keycodes from "/usr/include/linux/input-event-codes.h"
 
+use smithay_client_toolkit::seat::keyboard::RawModifiers;
+use xkbcommon::xkb;
+
 /// This is the code that maps to a physical button on the keyboard, irrespective of any locales
 /// or anything else. To get a name for the key, use the constants defined in this file from the
 /// below proc_macro `get_keys!()`. It sources its key names from "/usr/include/linux/input-event-codes.h".
 ///
+/// Prefer `Key` where you can, since these constants also include unrelated codes like `EV_SYN`.
+///
 /// This is needed because a `KeySym` press event will not always have a corresponding release
 /// event. Consider: Press A. -> KeySym::a event. Then press Shift. Then releasing A ->
 /// KeySym::A. a != A.
@@ -17,3 +22,148 @@ pub type RawKeyCode = u32;
 pub type KeySym = xkeysym::Keysym;
 
 include!(concat!(env!("OUT_DIR"), "/input_codes.rs"));
+
+impl Key {
+    /// The named variant for `Key::Unknown` codes that have one, like `Key::Unknown(30)` ->
+    /// `Key::KeyA`. Other keys are returned as they are
+    pub fn normalized(self) -> Self {
+        Key::from(u32::from(self))
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        u32::from(*self) == u32::from(*other)
+    }
+}
+
+impl Eq for Key {}
+
+impl std::hash::Hash for Key {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        u32::from(*self).hash(state);
+    }
+}
+
+impl std::fmt::Display for Key {
+    /// Writes the name of the constant, like `KEY_SPACE`, or the raw code for `Key::Unknown`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let code = u32::from(*self);
+        match name(code) {
+            Some(name) => write!(f, "{name}"),
+            None => write!(f, "{code}"),
+        }
+    }
+}
+
+/// The name of the constant for a key or button code, so `name(KEY_SPACE) == Some("KEY_SPACE")`.
+/// If a code has several names, like `BTN_MOUSE` and `BTN_LEFT`, the most specific one is given.
+/// # Example
+/// ```rust
+/// assert_eq!(wlib::keys::name(wlib::keys::BTN_MOUSE), Some("BTN_LEFT"));
+/// ```
+pub fn name(code: RawKeyCode) -> Option<&'static str> {
+    generated_name(code)
+}
+
+/// Looks up a key or button code by the name of its constant, for reading config files etc.
+/// # Example
+/// ```rust
+/// assert_eq!(wlib::keys::from_name("KEY_F5"), Some(wlib::keys::KEY_F5));
+/// assert_eq!(wlib::keys::from_name("EV_SYN"), None);
+/// ```
+pub fn from_name(name: &str) -> Option<RawKeyCode> {
+    generated_from_name(name)
+}
+
+/// The keyboard layout the compositor is using. Use it to find out what a `RawKeyCode` would type,
+/// for example to show "Press Z to jump" with the right letter on an AZERTY keyboard.
+#[derive(Clone)]
+pub struct Keymap {
+    keymap: xkb::Keymap,
+    modifiers: RawModifiers,
+    layout: u32,
+}
+
+impl Keymap {
+    pub(crate) fn from_string(keymap: String) -> Option<Self> {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let keymap = xkb::Keymap::new_from_string(
+            &context,
+            keymap,
+            xkb::KEYMAP_FORMAT_TEXT_V1,
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )?;
+
+        Some(Self {
+            keymap,
+            modifiers: RawModifiers::default(),
+            layout: 0,
+        })
+    }
+
+    pub(crate) fn set_modifiers(&mut self, modifiers: RawModifiers, layout: u32) {
+        self.modifiers = modifiers;
+        self.layout = layout;
+    }
+
+    /// The symbol pressing `code` would produce with the modifiers that are currently active
+    pub fn sym(&self, code: RawKeyCode) -> Option<KeySym> {
+        let mut state = xkb::State::new(&self.keymap);
+        state.update_mask(
+            self.modifiers.depressed,
+            self.modifiers.latched,
+            self.modifiers.locked,
+            0,
+            0,
+            self.layout,
+        );
+
+        Some(state.key_get_one_sym(xkb_keycode(code))).filter(|sym| *sym != KeySym::NoSymbol)
+    }
+
+    /// The symbol pressing `code` would produce without any modifiers held
+    pub fn base_sym(&self, code: RawKeyCode) -> Option<KeySym> {
+        self.keymap
+            .key_get_syms_by_level(xkb_keycode(code), self.layout, 0)
+            .first()
+            .copied()
+    }
+}
+
+impl std::fmt::Debug for Keymap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keymap")
+            .field("modifiers", &self.modifiers)
+            .field("layout", &self.layout)
+            .finish_non_exhaustive()
+    }
+}
+
+/// xkb key codes are offset by 8 from the evdev codes in "/usr/include/linux/input-event-codes.h"
+fn xkb_keycode(code: RawKeyCode) -> xkb::Keycode {
+    xkb::Keycode::new(code + 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_keys() {
+        let unknown = Key::Unknown(KEY_A);
+        assert_eq!(unknown, Key::KeyA);
+        assert!(matches!(unknown.normalized(), Key::KeyA));
+        assert_eq!(unknown.to_string(), "KEY_A");
+
+        let set: std::collections::HashSet<Key> = [Key::KeyA, unknown].into();
+        assert_eq!(set.len(), 1);
+
+        // Aliases map to the variant of the name they stand for
+        assert_eq!(from_name("BTN_A"), Some(BTN_SOUTH));
+        assert_eq!(Key::from(BTN_A), Key::BtnSouth);
+
+        // Codes without a name stay unknown
+        assert!(matches!(Key::from(0x2ff).normalized(), Key::Unknown(0x2ff)));
+    }
+}
@@ -1,47 +1,73 @@
+mod buffers;
+pub mod color;
+pub mod input;
 pub mod keys;
+pub mod scale;
 
 use std::collections::{HashMap, HashSet};
+use std::num::NonZeroU32;
+use std::sync::Arc;
 use std::time::Duration;
 
+use buffers::{BufferLayout, BufferPool, BufferRing, RetainedFrame};
+
 use smithay_client_toolkit::activation::RequestData;
 use smithay_client_toolkit::reexports::calloop::EventLoop;
 use smithay_client_toolkit::reexports::calloop_wayland_source::WaylandSource;
+use smithay_client_toolkit::reexports::csd_frame::{
+    DecorationsFrame, FrameAction, FrameClick, ResizeEdge as FrameResizeEdge,
+    WindowState as XdgWindowState,
+};
 use smithay_client_toolkit::{
     activation::{ActivationHandler, ActivationState},
-    compositor::{CompositorHandler, CompositorState},
+    compositor::{CompositorHandler, CompositorState, Region},
     delegate_activation, delegate_compositor, delegate_keyboard, delegate_output, delegate_pointer,
-    delegate_registry, delegate_seat, delegate_shm, delegate_xdg_shell, delegate_xdg_window,
+    delegate_registry, delegate_seat, delegate_shm, delegate_subcompositor, delegate_xdg_popup,
+    delegate_xdg_shell, delegate_xdg_window,
     output::{OutputHandler, OutputState},
     registry::{ProvidesRegistryState, RegistryState},
     registry_handlers,
     seat::{
         Capability, SeatHandler, SeatState,
         keyboard::{KeyEvent, KeyboardHandler, Keysym, Modifiers, RawModifiers},
-        pointer::PointerHandler,
+        pointer::{PointerData, PointerHandler},
     },
     shell::{
         WaylandSurface,
         xdg::{
-            XdgShell,
+            XdgPositioner, XdgShell, XdgSurface,
+            fallback_frame::FallbackFrame,
+            popup::{Popup, PopupConfigure, PopupHandler},
             window::{Window, WindowConfigure, WindowDecorations, WindowHandler},
         },
     },
-    shm::{
-        Shm, ShmHandler,
-        slot::{Buffer, SlotPool},
-    },
+    shm::{Shm, ShmHandler},
+    subcompositor::SubcompositorState,
 };
 
+use smithay_client_toolkit::reexports::protocols::wp::viewporter::client::{
+    wp_viewport::{self, WpViewport},
+    wp_viewporter::{self, WpViewporter},
+};
+use smithay_client_toolkit::reexports::protocols::xdg::shell::client::xdg_surface;
 use wayland_client::{
-    Connection, QueueHandle,
+    Connection, Dispatch, Proxy, QueueHandle,
     globals::registry_queue_init,
-    protocol::{wl_keyboard, wl_output, wl_pointer, wl_seat, wl_shm, wl_surface},
+    protocol::{wl_keyboard, wl_output, wl_pointer, wl_seat, wl_shm, wl_subsurface, wl_surface},
 };
 
+pub use buffers::{Buffering, PoolStats};
+pub use smithay_client_toolkit::output::OutputInfo;
+pub use smithay_client_toolkit::reexports::protocols::xdg::shell::client::xdg_positioner::{
+    Anchor, ConstraintAdjustment, Gravity,
+};
+pub use smithay_client_toolkit::reexports::protocols::xdg::shell::client::xdg_toplevel::ResizeEdge;
 pub use smithay_client_toolkit::seat::{
     keyboard,
     pointer::{PointerEvent, PointerEventKind},
 };
+pub use smithay_client_toolkit::shell::xdg::window::DecorationMode;
+pub use wayland_client::protocol::wl_output::WlOutput;
 
 // Todo.
 // -  More information to update like keyboard state.
@@ -50,11 +76,34 @@ pub use smithay_client_toolkit::seat::{
 pub trait WindowAble {
     /// Ran before draw so you can set up your scene with information from `context`
     /// You can include any requests you want wlib to do in in the returned output
-    fn update(&mut self, context: Context) -> Option<WLibRequest>;
+    ///
+    /// Only one request can be made per frame this way. Implement `update_requests` instead if you
+    /// need more.
+    fn update(&mut self, _context: Context) -> Option<WLibRequest> {
+        None
+    }
+
+    /// Like `update`, but any number of requests can be pushed onto `requests`. They are handled
+    /// in order once this returns.
+    /// By default this just calls `update`, so only implement one of the two.
+    /// # Example
+    /// ```rust
+    /// fn update_requests(context: wlib::Context, requests: &mut Vec<wlib::WLibRequest>) {
+    ///     if context.close_requested {
+    ///         requests.push(wlib::WLibRequest::SetTitle("Closing...".to_string()));
+    ///         requests.push(wlib::WLibRequest::CloseAccepted);
+    ///     }
+    /// }
+    /// ```
+    fn update_requests(&mut self, context: Context, requests: &mut Vec<WLibRequest>) {
+        requests.extend(self.update(context));
+    }
 
     /// Write your pixels to this buffer
     /// Since the window size is controlled by compositor, the width and height is given here.
-    /// Foramt is always ARGB little endian. (So real byte order is BGRA)
+    /// Format is ARGB little endian (so real byte order is BGRA), unless another format was picked
+    /// with `WLibSettings::with_pixel_format`. Implement `draw_frame` instead to find out which.
+    /// Alpha is premultiplied, so no color can be larger than alpha. See `wlib::color`
     /// # Example
     /// ```rust
     /// fn draw(buffer: &mut [u8], frame: wlib::WindowSize) {
@@ -78,7 +127,44 @@ pub trait WindowAble {
     ///         });
     /// }
     /// ```
-    fn draw(&mut self, pixel_buffer: &mut [u8], frame_info: WindowSize);
+    fn draw(&mut self, _pixel_buffer: &mut [u8], _frame_info: WindowSize) {}
+
+    /// Like `draw`, with more information about the buffer, like its pixel format.
+    /// By default this just calls `draw`, so only implement one of the two.
+    /// # Example
+    /// ```rust
+    /// fn draw_frame(buffer: &mut [u8], frame: &wlib::FrameInfo) {
+    ///     let red = match frame.format {
+    ///         wlib::PixelFormat::Abgr8888 | wlib::PixelFormat::Xbgr8888 => [0xFF, 0, 0, 0xFF],
+    ///         wlib::PixelFormat::Argb8888 | wlib::PixelFormat::Xrgb8888 => [0, 0, 0xFF, 0xFF],
+    ///         // Formats this app never asks for
+    ///         _ => return,
+    ///     };
+    ///     for pixel in buffer.chunks_exact_mut(4) {
+    ///         pixel.copy_from_slice(&red);
+    ///     }
+    /// }
+    /// ```
+    fn draw_frame(&mut self, pixel_buffer: &mut [u8], frame: &FrameInfo) {
+        self.draw(pixel_buffer, frame.size);
+    }
+
+    /// Ran once the window has its first size, before the first `draw`
+    fn init(&mut self, _context: &Context) {}
+
+    /// Ran when the window size changes, before the next `draw`. Use it to reallocate anything
+    /// that depends on the size, like a z-buffer
+    fn resized(&mut self, _old: WindowSize, _new: WindowSize) {}
+
+    /// Ran when the window gains or loses keyboard focus
+    fn focus_changed(&mut self, _focused: bool) {}
+
+    /// Ran when the compositor asks for the window to close, for example the user clicked the close
+    /// button. The window stays open until you send `WLibRequest::CloseAccepted`
+    fn close_requested(&mut self) {}
+
+    /// Ran once the window is closing. For the last open window, this is right before `run` returns
+    fn exiting(&mut self) {}
 }
 
 /// The possible event types you can get from `Context::event_queue`
@@ -87,21 +173,142 @@ pub enum Event {
     KeyPress(KeyEvent),
     KeyRelease(KeyEvent),
     PointerEvent(PointerEvent),
+    /// A mouse button was pressed. `count` is 1 for a single click, 2 for a double click, 3 for a
+    /// triple click and so on. See `WLibSettings::with_double_click_interval` and
+    /// `WLibSettings::with_double_click_distance` for what counts as a repeated click.
+    Click {
+        button: MouseButton,
+        position: (f64, f64),
+        count: u32,
+    },
     CloseRequested,
+    /// The compositor changed `Context::window_state`, `Context::bounds` or
+    /// `Context::decoration_mode`
+    WindowStateChanged,
+    /// The window gained or lost keyboard focus. See `Context::has_keyboard_focus`
+    KeyboardFocusChanged(bool),
+    /// The mouse moved onto or off the window. See `Context::is_pointer_inside`
+    PointerInsideChanged(bool),
+    /// The compositor made the window the active one, or stopped. See `WindowState::activated`
+    ActivatedChanged(bool),
+    /// The compositor closed a popup opened by this window, for example because the user clicked
+    /// outside of it. The popup's `WindowAble` gets `exiting` called as usual
+    PopupDismissed,
 }
 
 /// Some information you want to tell WLib.
 pub enum WLibRequest {
     /// This is so you can prompt for the user to save their work or confirm exit etc, before
-    /// closing the window
+    /// closing the window. `run` returns once the last window is closed
     CloseAccepted,
+
+    /// Change the title shown in window selectors, decorations etc.
+    SetTitle(String),
+
+    /// Change the app id. See `WLibSettings::with_app_id`
+    SetAppId(String),
+
+    /// Tell the compositor the smallest size the window can be. `None` removes the limit
+    SetMinSize(Option<WindowSize>),
+
+    /// Tell the compositor the largest size the window can be. `None` removes the limit
+    SetMaxSize(Option<WindowSize>),
+
+    /// Resize the window. Compositors that manage the window size themselves, like tiling
+    /// window managers, will change it back on the next configure. Sizes with a zero width or
+    /// height are ignored, and sizes past `WLibSettings::with_max_size` are shrunk to fit it.
+    /// Ignored while the window is maximized, fullscreen or tiled, since it has to keep the size
+    /// the compositor gave it.
+    RequestSize(WindowSize),
+
+    /// Make the window fullscreen. Pick a monitor from `Context::outputs`, or `None` to let the
+    /// compositor decide
+    SetFullscreen(Option<WlOutput>),
+
+    UnsetFullscreen,
+
+    Maximize,
+
+    Unmaximize,
+
+    /// Minimize the window. There is no way to unminimize it, that is up to the user.
+    Minimize,
+
+    /// Start dragging the window around, for your own title bar when using
+    /// `WLibSettings::with_decorations(None)`. Send it in the same frame as the mouse press that
+    /// started the drag, since the compositor checks that a button is held.
+    BeginMove,
+
+    /// Start resizing the window from `ResizeEdge`, for your own borders. Like `BeginMove`, send it
+    /// in the same frame as the mouse press
+    BeginResize(ResizeEdge),
+
+    /// Show the compositor's window menu (minimize, move to workspace etc) at the mouse position
+    ShowWindowMenu,
+
+    /// Open another top level window, drawn by its own `WindowAble`. Requests from each
+    /// `WindowAble` only affect its own window, and `run` keeps going until every window is closed
+    OpenWindow(Box<dyn WindowAble>, WLibSettings),
+
+    /// Open a popup, like a context menu or tooltip, drawn by its own `WindowAble`. It is placed
+    /// relative to this window (or popup) and can extend past its edges. Popups close when their
+    /// parent does, and requests that only make sense for top level windows, like `SetTitle`, are
+    /// ignored for them
+    OpenPopup(Box<dyn WindowAble>, PopupSettings),
+
+    /// Open a layer on top of this window, drawn by its own `WindowAble` with its own buffer size
+    /// and frame rate. Good for overlays like a HUD. It closes when its parent does
+    OpenSubsurface(Box<dyn WindowAble>, SubsurfaceSettings),
+
+    /// Subsurfaces only: move to this position, relative to the top left of the parent
+    SetPosition(i32, i32),
+
+    /// Subsurfaces only: draw on top of the parent. This is the default
+    PlaceAboveParent,
+
+    /// Subsurfaces only: draw underneath the parent, so it only shows through transparent parts
+    PlaceBelowParent,
+
+    /// Subsurfaces only: see `SubsurfaceSettings::with_sync`
+    SetSync(bool),
+
+    /// Mark the whole window as opaque, or go back to `SetOpaqueRegion`'s region. See
+    /// `WLibSettings::with_opaque`
+    SetOpaque(bool),
+
+    /// Tell the compositor which parts of the window are fully opaque. In window pixels, even with
+    /// a virtual resolution. See `WLibSettings::with_opaque_region`
+    SetOpaqueRegion(Vec<Rect>),
+
+    /// Only take mouse input inside these rectangles, clicks elsewhere go to whatever is behind
+    /// the window. `None` takes input everywhere. See `WLibSettings::with_input_region`
+    SetInputRegion(Option<Vec<Rect>>),
+
+    /// Tell the compositor only this part of the window changed this frame, so it can skip
+    /// redrawing the rest. Send one for each changed area. Without any, the whole window counts as
+    /// changed. In app pixels. With a virtual resolution the whole window is always damaged.
+    /// Unless `WLibSettings::with_retained` is on, the buffer still has to be fully drawn
+    Damage(Rect),
 }
 
 /// The information passed to your `update()` each frame
 #[derive(Debug, Clone)]
 pub struct Context {
     /// The currently pressed keys. Just a convinence field for the event_queue
-    pub pressed_keys: HashMap<keys::RawKeyCode, keys::KeySym>,
+    pub pressed_keys: HashMap<keys::Key, keys::KeySym>,
+
+    /// Keys that went down since the last frame
+    pub keys_pressed_this_frame: HashMap<keys::Key, keys::KeySym>,
+
+    /// Keys that went up since the last frame. Includes keys that were held when the window lost
+    /// keyboard focus
+    pub keys_released_this_frame: HashMap<keys::Key, keys::KeySym>,
+
+    /// Which modifier keys (ctrl, shift etc) are currently active
+    pub modifiers: keyboard::Modifiers,
+
+    /// The current keyboard layout. `None` until the compositor has sent one
+    pub keymap: Option<keys::Keymap>,
 
     /// State of the mouse
     pub mouse_state: MouseState,
@@ -113,15 +320,95 @@ pub struct Context {
     /// To accept, return a `WLibRequest::CloseAccepted`.
     pub close_requested: bool,
 
-    /// Self explanatory
+    /// Does the window get keyboard input? Pause your game when this goes false.
+    /// See also `window_state.activated`, which is what the compositor shows as the active window
+    pub has_keyboard_focus: bool,
+
+    /// Is the mouse over the window?
+    pub is_pointer_inside: bool,
+
+    /// Despite the name, whether the mouse is over the window. Kept the same as
+    /// `is_pointer_inside`
+    #[deprecated(note = "use `is_pointer_inside`, or `has_keyboard_focus` for keyboard input")]
     pub is_window_focused: bool,
 
     /// List of events since the last frame.
     /// Use it if you specfically need mouse/key(up/down) events or specfic mouse motions.
     pub event_queue: Vec<Event>,
 
-    /// Current size of the window
+    /// Current size of the window. If `WLibSettings::with_virtual_resolution` is used, this is the
+    /// virtual resolution instead
     pub window_size: WindowSize,
+
+    /// The monitors that are connected
+    pub outputs: Vec<Output>,
+
+    /// Whether the window is maximized, fullscreen etc.
+    pub window_state: WindowState,
+
+    /// The largest size the compositor thinks the window should be, so it fits on screen.
+    /// `None` if the compositor doesn't say
+    pub bounds: Option<WindowSize>,
+
+    /// Whether the compositor is drawing the title bar and borders, or the window has to
+    pub decoration_mode: DecorationMode,
+
+    /// Memory used by the buffers of all windows, for debugging
+    pub pool_stats: PoolStats,
+}
+
+/// The state of the window set by the compositor
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WindowState {
+    pub maximized: bool,
+    pub fullscreen: bool,
+    /// The window is the one the user is interacting with. Usually drawn with a highlighted title
+    /// bar
+    pub activated: bool,
+    /// The user is dragging to resize the window
+    pub resizing: bool,
+    /// The left edge is against another window or the edge of the screen
+    pub tiled_left: bool,
+    pub tiled_right: bool,
+    pub tiled_top: bool,
+    pub tiled_bottom: bool,
+    /// The window isn't visible, so there is no point drawing. For example it is on another
+    /// workspace
+    pub suspended: bool,
+}
+
+impl WindowState {
+    fn from_configure(configure: &WindowConfigure) -> Self {
+        Self {
+            maximized: configure.is_maximized(),
+            fullscreen: configure.is_fullscreen(),
+            activated: configure.is_activated(),
+            resizing: configure.is_resizing(),
+            tiled_left: configure.is_tiled_left(),
+            tiled_right: configure.is_tiled_right(),
+            tiled_top: configure.is_tiled_top(),
+            tiled_bottom: configure.is_tiled_bottom(),
+            suspended: configure.state.contains(XdgWindowState::SUSPENDED),
+        }
+    }
+
+    /// Maximized, fullscreen and tiled windows have to be the size they are given
+    fn has_fixed_size(&self) -> bool {
+        self.maximized
+            || self.fullscreen
+            || self.tiled_left
+            || self.tiled_right
+            || self.tiled_top
+            || self.tiled_bottom
+    }
+}
+
+/// A monitor the window can be shown on
+#[derive(Debug, Clone)]
+pub struct Output {
+    pub wl_output: WlOutput,
+    /// Name, size, position etc. `None` until the compositor has described the output
+    pub info: Option<OutputInfo>,
 }
 
 /// State of the mouse
@@ -131,6 +418,60 @@ pub struct MouseState {
     pub position: (f64, f64),
     /// The mouse buttons currently pressed. Just a convinence field for the event_queue
     pub mouse_buttons_pressed: HashSet<MouseButton>,
+    /// The mouse buttons that went down since the last frame
+    pub mouse_buttons_pressed_this_frame: HashSet<MouseButton>,
+    /// The mouse buttons that went up since the last frame
+    pub mouse_buttons_released_this_frame: HashSet<MouseButton>,
+}
+
+impl Context {
+    /// Sets `is_pointer_inside`, and the deprecated field that follows it
+    #[allow(deprecated)]
+    fn set_pointer_inside(&mut self, inside: bool) {
+        self.is_pointer_inside = inside;
+        self.is_window_focused = inside;
+    }
+
+    /// Is this key or mouse button currently held down?
+    /// # Example
+    /// ```rust
+    /// fn update(context: &wlib::Context) {
+    ///     if context.is_down(wlib::keys::Key::KeyW) || context.is_down(wlib::MouseButton::BtnLeft) {
+    ///         // Move forward
+    ///     }
+    /// }
+    /// ```
+    pub fn is_down(&self, input: impl Into<input::Input>) -> bool {
+        match input.into() {
+            input::Input::Key(key) => self.pressed_keys.contains_key(&key),
+            input::Input::Sym(sym) => self.pressed_keys.values().any(|s| *s == sym),
+            input::Input::Mouse(button) => self.mouse_state.mouse_buttons_pressed.contains(&button),
+        }
+    }
+
+    /// Did this key or mouse button go down since the last frame?
+    pub fn was_pressed(&self, input: impl Into<input::Input>) -> bool {
+        match input.into() {
+            input::Input::Key(key) => self.keys_pressed_this_frame.contains_key(&key),
+            input::Input::Sym(sym) => self.keys_pressed_this_frame.values().any(|s| *s == sym),
+            input::Input::Mouse(button) => self
+                .mouse_state
+                .mouse_buttons_pressed_this_frame
+                .contains(&button),
+        }
+    }
+
+    /// Did this key or mouse button go up since the last frame?
+    pub fn was_released(&self, input: impl Into<input::Input>) -> bool {
+        match input.into() {
+            input::Input::Key(key) => self.keys_released_this_frame.contains_key(&key),
+            input::Input::Sym(sym) => self.keys_released_this_frame.values().any(|s| *s == sym),
+            input::Input::Mouse(button) => self
+                .mouse_state
+                .mouse_buttons_released_this_frame
+                .contains(&button),
+        }
+    }
 }
 
 /// Supported MouseButtons.
@@ -147,6 +488,20 @@ pub enum MouseButton {
     BtnBack,
 }
 
+impl From<MouseButton> for u32 {
+    fn from(value: MouseButton) -> Self {
+        match value {
+            MouseButton::BtnLeft => 0x110,
+            MouseButton::BtnRight => 0x111,
+            MouseButton::BtnMiddle => 0x112,
+            MouseButton::BtnSide => 0x113,
+            MouseButton::BtnExtra => 0x114,
+            MouseButton::BtnForward => 0x115,
+            MouseButton::BtnBack => 0x116,
+        }
+    }
+}
+
 impl TryFrom<u32> for MouseButton {
     type Error = ();
 
@@ -164,42 +519,270 @@ impl TryFrom<u32> for MouseButton {
     }
 }
 
+/// The serial of a button press. Moves, resizes, window menus and popup grabs need one, and
+/// compositors reject release serials for them
+fn press_serial(kind: &PointerEventKind) -> Option<u32> {
+    match kind {
+        PointerEventKind::Press { serial, .. } => Some(*serial),
+        _ => None,
+    }
+}
+
+/// Keeps track of the last button press so repeated presses can be counted as double/triple clicks
+#[derive(Default)]
+struct ClickCounter {
+    last_button: Option<MouseButton>,
+    last_time: u32,
+    last_position: (f64, f64),
+    count: u32,
+}
+
+impl ClickCounter {
+    /// Registers a press and returns how many times in a row this button has been clicked.
+    /// `time` is the millisecond timestamp from the compositor
+    fn press(
+        &mut self,
+        button: MouseButton,
+        time: u32,
+        position: (f64, f64),
+        interval: Duration,
+        distance: f64,
+    ) -> u32 {
+        let elapsed = time.wrapping_sub(self.last_time) as u128;
+        let dx = position.0 - self.last_position.0;
+        let dy = position.1 - self.last_position.1;
+
+        let is_repeat = self.last_button.as_ref() == Some(&button)
+            && elapsed <= interval.as_millis()
+            && (dx * dx + dy * dy).sqrt() <= distance;
+
+        self.count = if is_repeat { self.count + 1 } else { 1 };
+        self.last_button = Some(button);
+        self.last_time = time;
+        self.last_position = position;
+
+        self.count
+    }
+}
+
 struct WindowManager {
     registry_state: RegistryState,
     seat_state: SeatState,
     output_state: OutputState,
+    compositor: CompositorState,
+    xdg_shell: XdgShell,
     shm: Shm,
     xdg_activation: Option<ActivationState>,
+    /// Needed to draw our own title bar when the compositor won't
+    subcompositor: Option<Arc<SubcompositorState>>,
+    /// Lets the compositor scale up virtual resolutions
+    viewporter: Option<WpViewporter>,
+
+    /// Holds the buffers of every window
+    pool: BufferPool,
+    windows: Vec<ManagedWindow>,
+
+    keyboard: Option<wl_keyboard::WlKeyboard>,
+    pointer: Option<wl_pointer::WlPointer>,
+    /// Surface of the window that gets key presses
+    keyboard_focus: Option<wl_surface::WlSurface>,
+    /// Surface of the window whose title bar or borders the pointer is over
+    frame_pointer_focus: Option<wl_surface::WlSurface>,
+    /// The seat and serial of the latest button press, needed for moving, resizing and popup grabs
+    last_button_serial: Option<(wl_seat::WlSeat, u32)>,
+
+    // Copied into the `Context` of windows opened later
+    outputs: Vec<Output>,
+    modifiers: Modifiers,
+    keymap: Option<keys::Keymap>,
+}
 
+/// A top level window or popup, and the `WindowAble` that draws it
+struct ManagedWindow {
     close_accepted: bool,
     first_configure: bool,
-    pool: SlotPool,
     width: u32,
     height: u32,
-    buffer: Option<Buffer>,
-    window: Window,
-    keyboard: Option<wl_keyboard::WlKeyboard>,
-    keyboard_focus: bool,
-    pointer: Option<wl_pointer::WlPointer>,
+    buffers: BufferRing,
+    role: SurfaceRole,
+    /// The title bar and borders we draw when the decoration mode is `DecorationMode::Client`
+    window_frame: Option<FallbackFrame<WindowManager>>,
     last_frame_time: Option<std::time::Instant>,
-
-    managed_window: Box<dyn WindowAble>,
+    click_counter: ClickCounter,
+    /// What the app draws into when using a virtual resolution
+    virtual_buffer: Vec<u8>,
+    /// Scales the buffer up when using `Scaler::Compositor`
+    viewport: Option<WpViewport>,
+    /// The opaque or input region changed, so is sent with the next draw
+    regions_dirty: bool,
+    /// Whether `WLibSettings::with_alpha_validation` already warned about this window
+    invalid_alpha_reported: bool,
+    /// `WLibRequest::Damage`s for the next draw
+    damage: Vec<Rect>,
+    /// The last frame, for `WLibSettings::with_retained`
+    retained: RetainedFrame,
+
+    state: Box<dyn WindowAble>,
     settings: WLibSettings,
     context: Context,
 }
 
+/// What a `ManagedWindow`'s surface is shown as
+enum SurfaceRole {
+    Window(Window),
+    Popup {
+        popup: Popup,
+        /// The window or popup it was opened from
+        parent: wl_surface::WlSurface,
+    },
+    Subsurface {
+        subsurface: wl_subsurface::WlSubsurface,
+        surface: wl_surface::WlSurface,
+        parent: wl_surface::WlSurface,
+    },
+}
+
+impl SurfaceRole {
+    fn wl_surface(&self) -> &wl_surface::WlSurface {
+        match self {
+            SurfaceRole::Window(window) => window.wl_surface(),
+            SurfaceRole::Popup { popup, .. } => popup.wl_surface(),
+            SurfaceRole::Subsurface { surface, .. } => surface,
+        }
+    }
+
+    /// `None` for subsurfaces, which aren't shown on their own
+    fn xdg_surface(&self) -> Option<&xdg_surface::XdgSurface> {
+        match self {
+            SurfaceRole::Window(window) => Some(window.xdg_surface()),
+            SurfaceRole::Popup { popup, .. } => Some(popup.xdg_surface()),
+            SurfaceRole::Subsurface { .. } => None,
+        }
+    }
+
+    /// The surface this one closes with
+    fn parent(&self) -> Option<&wl_surface::WlSurface> {
+        match self {
+            SurfaceRole::Window(_) => None,
+            SurfaceRole::Popup { parent, .. } | SurfaceRole::Subsurface { parent, .. } => {
+                Some(parent)
+            }
+        }
+    }
+}
+
+/// How the pixels in the buffer passed to `WindowAble::draw` are laid out. Named from the most to
+/// least significant bits of a little endian number, so `Argb8888` is stored as the bytes B, G, R,
+/// A. Formats without alpha (`X`) are always opaque, which is cheaper for the compositor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum PixelFormat {
+    /// 8 bits each of alpha, red, green and blue. Always available. The default
+    #[default]
+    Argb8888,
+    /// Like `Argb8888` but the alpha byte is ignored. Always available
+    Xrgb8888,
+    /// 8 bits each of alpha, blue, green and red, stored as the bytes R, G, B, A
+    Abgr8888,
+    /// Like `Abgr8888` but the alpha byte is ignored
+    Xbgr8888,
+    /// 5 bits red, 6 bits green and 5 bits blue in 2 bytes
+    Rgb565,
+    /// 10 bits each of red, green and blue in 4 bytes. The top 2 bits are ignored
+    Xrgb2101010,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> u32 {
+        match self {
+            PixelFormat::Rgb565 => 2,
+            _ => 4,
+        }
+    }
+
+    /// Opaque black in this format. Only the first `bytes_per_pixel` bytes are used
+    pub fn black(self) -> [u8; 4] {
+        match self {
+            PixelFormat::Argb8888 | PixelFormat::Abgr8888 => [0, 0, 0, 0xFF],
+            PixelFormat::Xrgb8888
+            | PixelFormat::Xbgr8888
+            | PixelFormat::Rgb565
+            | PixelFormat::Xrgb2101010 => [0; 4],
+        }
+    }
+
+    pub fn has_alpha(self) -> bool {
+        matches!(self, PixelFormat::Argb8888 | PixelFormat::Abgr8888)
+    }
+
+    fn wl_format(self) -> wl_shm::Format {
+        match self {
+            PixelFormat::Argb8888 => wl_shm::Format::Argb8888,
+            PixelFormat::Xrgb8888 => wl_shm::Format::Xrgb8888,
+            PixelFormat::Abgr8888 => wl_shm::Format::Abgr8888,
+            PixelFormat::Xbgr8888 => wl_shm::Format::Xbgr8888,
+            PixelFormat::Rgb565 => wl_shm::Format::Rgb565,
+            PixelFormat::Xrgb2101010 => wl_shm::Format::Xrgb2101010,
+        }
+    }
+
+    /// `self` if the compositor supports it, otherwise one of the formats every compositor has
+    fn or_supported(self, supported: &[wl_shm::Format]) -> Self {
+        if supported.contains(&self.wl_format()) {
+            self
+        } else if self.has_alpha() {
+            PixelFormat::Argb8888
+        } else {
+            PixelFormat::Xrgb8888
+        }
+    }
+}
+
+/// Information about the buffer passed to `WindowAble::draw_frame`
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameInfo {
+    /// Size in pixels
+    pub size: WindowSize,
+    /// Bytes from the start of one row to the next
+    pub stride: u32,
+    /// The format the compositor accepted. May not be the one asked for with
+    /// `WLibSettings::with_pixel_format`, if the compositor doesn't support it
+    pub format: PixelFormat,
+}
+
+/// The largest width or height `WLibRequest::RequestSize`, popups and subsurfaces go up to.
+/// Larger than any screen, and small enough for the buffer size to fit in an `i32`
+const MAX_REQUESTED_SIZE: u32 = 16384;
+
+/// A rectangle in surface pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
 /// The pixel size of a window
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct WindowSize {
     pub width: u32,
     pub height: u32,
 }
 
 /// The available settings to configure window stuff
-#[derive(Default)]
 pub struct WLibSettings {
-    /// If the window size should be static instead of updating when needed.
-    window_static_size: Option<WindowSize>,
+    /// The size to start at, and to use whenever the compositor lets the window pick
+    size: WindowSize,
+
+    /// The window is never made smaller than this
+    min_size: Option<WindowSize>,
+
+    /// The window is never made larger than this
+    max_size: Option<WindowSize>,
+
+    /// Width to height ratio the window is kept at
+    aspect_ratio: Option<(u32, u32)>,
 
     /// Title of the window to show in in window selectors, decorations etc.
     window_title: String,
@@ -207,6 +790,74 @@ pub struct WLibSettings {
     /// App Id. Should be [reverse domain
     /// notation](https://en.wikipedia.org/wiki/Reverse_domain_name_notation)
     app_id: String,
+
+    /// If the window should start fullscreen
+    fullscreen: bool,
+
+    /// Who draws the title bar and borders. `None` for no decorations at all
+    decorations: Option<DecorationMode>,
+
+    /// Maximum time between two presses for them to count as a double click
+    double_click_interval: Duration,
+
+    /// Maximum distance in pixels the mouse can move between presses of a double click
+    double_click_distance: f64,
+
+    /// The fixed size the app draws at, and how it is fit into the window
+    virtual_resolution: Option<(WindowSize, scale::Letterbox)>,
+
+    /// What scales the virtual resolution up to the window size
+    scaler: scale::Scaler,
+
+    /// The buffer format to use, if the compositor supports it
+    pixel_format: PixelFormat,
+
+    /// Treat the whole window as opaque, ignoring `opaque_region`
+    opaque: bool,
+
+    /// The parts of the window that are fully opaque
+    opaque_region: Vec<Rect>,
+
+    /// The parts of the window that take mouse input. `None` for all of it
+    input_region: Option<Vec<Rect>>,
+
+    /// Check each frame for colors that are not premultiplied
+    validate_alpha: bool,
+
+    /// How many buffers to draw into
+    buffering: Buffering,
+
+    /// Keep the last frame in the buffer given to `draw`
+    retained: bool,
+}
+
+impl Default for WLibSettings {
+    fn default() -> Self {
+        Self {
+            size: WindowSize {
+                width: 256,
+                height: 256,
+            },
+            min_size: None,
+            max_size: None,
+            aspect_ratio: None,
+            window_title: String::new(),
+            app_id: String::new(),
+            fullscreen: false,
+            decorations: Some(DecorationMode::Server),
+            double_click_interval: Duration::from_millis(400),
+            double_click_distance: 4.0,
+            virtual_resolution: None,
+            scaler: scale::Scaler::Software,
+            pixel_format: PixelFormat::Argb8888,
+            opaque: false,
+            opaque_region: Vec::new(),
+            input_region: None,
+            validate_alpha: false,
+            buffering: Buffering::Double,
+            retained: false,
+        }
+    }
 }
 
 impl WLibSettings {
@@ -214,151 +865,544 @@ impl WLibSettings {
         Self::default()
     }
 
-    pub fn with_static_size(mut self, size: WindowSize) -> Self {
-        self.window_static_size = Some(size);
+    /// Stop the window from being resized. Same as setting the size, min size and max size to
+    /// `size`
+    pub fn with_static_size(self, size: WindowSize) -> Self {
+        self.with_size(size).with_min_size(size).with_max_size(size)
+    }
+
+    /// The size the window starts at, if the compositor lets it choose. Defaults to 256x256
+    pub fn with_size(mut self, size: WindowSize) -> Self {
+        self.size = size;
         self
     }
 
-    pub fn with_title(mut self, title: &str) -> Self {
-        self.window_title = title.to_string();
+    pub fn with_min_size(mut self, size: WindowSize) -> Self {
+        self.min_size = Some(size);
         self
     }
 
-    pub fn with_app_id(mut self, id: &str) -> Self {
-        self.app_id = id.to_string();
+    pub fn with_max_size(mut self, size: WindowSize) -> Self {
+        self.max_size = Some(size);
         self
     }
-}
 
-/// Runs a struct implementing `WindowAble` by setting up a wayland event loop.
-pub fn run(state: Box<dyn WindowAble>, settings: WLibSettings) {
-    // All Wayland apps start by connecting the compositor (server).
-    let conn = Connection::connect_to_env().unwrap();
+    /// Keep the window at this width to height ratio, for example `with_aspect_ratio(16, 9)`.
+    /// Compositors can't be told about this, so if they ask for another shape the buffer is
+    /// shrunk to fit inside the size they asked for.
+    pub fn with_aspect_ratio(mut self, width: u32, height: u32) -> Self {
+        self.aspect_ratio = Some((width.max(1), height.max(1)));
+        self
+    }
 
-    // Enumerate the list of globals to get the protocols the server implements.
-    let (globals, event_queue) = registry_queue_init(&conn).unwrap();
-    let qh = event_queue.handle();
-    let mut event_loop: EventLoop<WindowManager> =
-        EventLoop::try_new().expect("Failed to initialize the event loop!");
-    let loop_handle = event_loop.handle();
-    WaylandSource::new(conn.clone(), event_queue)
-        .insert(loop_handle)
-        .unwrap();
+    /// The size to resize to for a `WLibRequest::RequestSize`, or `None` if it should be ignored
+    fn requested_size(
+        &self,
+        size: WindowSize,
+        current: WindowSize,
+        state: &WindowState,
+    ) -> Option<WindowSize> {
+        if size.width == 0 || size.height == 0 || state.has_fixed_size() {
+            return None;
+        }
+        let width = size.width.min(MAX_REQUESTED_SIZE);
+        let height = size.height.min(MAX_REQUESTED_SIZE);
+        Some(self.choose_size((Some(width), Some(height)), current, state))
+    }
 
-    // The compositor (not to be confused with the server which is commonly called the compositor) allows
-    // configuring surfaces to be presented.
-    let compositor = CompositorState::bind(&globals, &qh).expect("wl_compositor not available");
-    // For desktop platforms, the XDG shell is the standard protocol for creating desktop windows.
-    let xdg_shell = XdgShell::bind(&globals, &qh).expect("xdg shell is not available");
+    /// Picks the buffer size for a configure. Dimensions the compositor leaves up to us keep
+    /// their `current` value
+    fn choose_size(
+        &self,
+        suggested: (Option<u32>, Option<u32>),
+        current: WindowSize,
+        state: &WindowState,
+    ) -> WindowSize {
+        let mut width = suggested.0.unwrap_or(current.width);
+        let mut height = suggested.1.unwrap_or(current.height);
+
+        let constrained = !state.has_fixed_size();
+
+        if constrained && let Some(min) = self.min_size {
+            width = width.max(min.width);
+            height = height.max(min.height);
+        }
+        if constrained && let Some(max) = self.max_size {
+            width = width.min(max.width);
+            height = height.min(max.height);
+        }
 
-    // Since we are not using the GPU in this example, we use wl_shm to allow software rendering to a buffer
-    // we share with the compositor process.
-    let shm = Shm::bind(&globals, &qh).expect("wl shm is not available.");
-    // If the compositor supports xdg-activation it probably wants us to use it to get focus
-    let xdg_activation = ActivationState::bind(&globals, &qh).ok();
+        // Shrink whichever side is too long
+        if constrained && let Some((ratio_width, ratio_height)) = self.aspect_ratio {
+            let (width_64, height_64) = (width as u64, height as u64);
+            if width_64 * ratio_height as u64 > height_64 * ratio_width as u64 {
+                width = (height_64 * ratio_width as u64 / ratio_height as u64) as u32;
+            } else {
+                height = (width_64 * ratio_height as u64 / ratio_width as u64) as u32;
+            }
 
-    // A window is created from a surface.
-    let surface = compositor.create_surface(&qh);
-
-    // And then we can create the window.
-    let window = xdg_shell.create_window(surface, WindowDecorations::RequestServer, &qh);
-
-    // Configure the window, this may include hints to the compositor about the desired minimum size of the
-    // window, app id for WM identification, the window title, etc.
-    window.set_title(&settings.window_title);
-
-    // GitHub does not let projects use the `org.github` domain but the `io.github` domain is fine.
-    window.set_app_id(&settings.app_id);
-
-    // In order for the window to be mapped, we need to perform an initial commit with no attached buffer.
-    // For more info, see WaylandSurface::commit
-    //
-    // The compositor will respond with an initial configure that we can then use to present to the window with
-    // the correct options.
-    window.commit();
-
-    // To request focus, we first need to request a token
-    if let Some(activation) = xdg_activation.as_ref() {
-        activation.request_token(
-            &qh,
-            RequestData {
-                seat_and_serial: None,
-                surface: Some(window.wl_surface().clone()),
-                app_id: Some(String::from(
-                    "io.github.smithay.client-toolkit.SimpleWindow",
-                )),
-            },
-        )
-    }
+            // The minimum size wins over the aspect ratio
+            if let Some(min) = self.min_size {
+                width = width.max(min.width);
+                height = height.max(min.height);
+            }
+        }
 
-    let (width, height) = if let Some(ref dimensions) = settings.window_static_size {
-        // If both min and max size are set to the same value, it means the size is static.
-        // from (niri docs)[https://github.com/YaLTeR/niri/wiki/Floating-Windows], if this is the
-        // case the window is set to be floating in tiling window managers
-        window.set_min_size(Some((dimensions.width, dimensions.height)));
-        window.set_max_size(Some((dimensions.width, dimensions.height)));
+        WindowSize {
+            width: width.max(1),
+            height: height.max(1),
+        }
+    }
 
-        (dimensions.width, dimensions.height)
-    } else {
-        (200, 200)
-    };
+    pub fn with_title(mut self, title: &str) -> Self {
+        self.window_title = title.to_string();
+        self
+    }
 
-    // We don't know how large the window will be yet, so lets assume the minimum size we suggested for the
-    // initial memory allocation.
-    let pool = SlotPool::new((width * height * 4) as usize, &shm).expect("Failed to create pool");
+    pub fn with_app_id(mut self, id: &str) -> Self {
+        self.app_id = id.to_string();
+        self
+    }
 
-    let mut window_manager = WindowManager {
-        // Seats and outputs may be hotplugged at runtime, therefore we need to setup a registry state to
-        // listen for seats and outputs.
-        registry_state: RegistryState::new(&globals),
-        seat_state: SeatState::new(&globals, &qh),
-        output_state: OutputState::new(&globals, &qh),
-        shm,
-        xdg_activation,
+    /// Who should draw the title bar and borders.
+    /// - `Some(DecorationMode::Server)`: The compositor, falling back to a simple title bar drawn
+    ///   by wlib if the compositor can't. This is the default.
+    /// - `Some(DecorationMode::Client)`: Always use wlib's title bar
+    /// - `None`: No decorations. Use `WLibRequest::BeginMove` and `WLibRequest::BeginResize` to
+    ///   make your own
+    pub fn with_decorations(mut self, decorations: Option<DecorationMode>) -> Self {
+        self.decorations = decorations;
+        self
+    }
 
-        close_accepted: false,
-        first_configure: true,
-        pool,
-        width,
-        height,
-        buffer: None,
-        window,
-        keyboard: None,
-        keyboard_focus: false,
-        pointer: None,
-        last_frame_time: None,
-
-        managed_window: state,
-        context: Context {
-            delta_time: std::time::Duration::from_millis(0),
-            pressed_keys: HashMap::new(),
-            close_requested: false,
-            event_queue: Vec::new(),
-            is_window_focused: true,
-
-            mouse_state: MouseState {
-                position: (0.0, 0.0),
-                mouse_buttons_pressed: HashSet::new(),
-            },
-            window_size: WindowSize {
-                height: 0,
-                width: 0,
-            },
-        },
-        settings,
-    };
+    /// Start the window fullscreen, on whichever monitor the compositor picks
+    pub fn with_fullscreen(mut self, fullscreen: bool) -> Self {
+        self.fullscreen = fullscreen;
+        self
+    }
 
-    // We don't draw immediately, the configure will notify us when to first draw.
-    loop {
-        event_loop
-            .dispatch(Duration::ZERO, &mut window_manager)
-            .unwrap();
+    /// Maximum time between presses for `Event::Click` to count them as one multi-click.
+    /// Defaults to 400ms
+    pub fn with_double_click_interval(mut self, interval: Duration) -> Self {
+        self.double_click_interval = interval;
+        self
+    }
 
-        if window_manager.close_accepted {
-            break;
-        }
+    /// Maximum distance in pixels between presses for `Event::Click` to count them as one
+    /// multi-click. Defaults to 4 pixels
+    pub fn with_double_click_distance(mut self, distance: f64) -> Self {
+        self.double_click_distance = distance;
+        self
     }
-}
+
+    /// Always draw at `size`, no matter how large the window is. The image is scaled up to fill the
+    /// window, with `letterbox` deciding what happens when the window is a different shape. Pointer
+    /// positions are converted to virtual pixels, and `Context::window_size` is always `size`.
+    /// # Example
+    /// ```rust
+    /// use wlib::scale::Letterbox;
+    ///
+    /// let settings = wlib::WLibSettings::new().with_virtual_resolution(
+    ///     wlib::WindowSize {
+    ///         width: 320,
+    ///         height: 200,
+    ///     },
+    ///     Letterbox::IntegerScale,
+    /// );
+    /// ```
+    pub fn with_virtual_resolution(
+        mut self,
+        size: WindowSize,
+        letterbox: scale::Letterbox,
+    ) -> Self {
+        let size = WindowSize {
+            width: size.width.max(1),
+            height: size.height.max(1),
+        };
+        self.virtual_resolution = Some((size, letterbox));
+        self
+    }
+
+    /// Who scales up the virtual resolution. Defaults to `Scaler::Software`. Does nothing without
+    /// `with_virtual_resolution`
+    pub fn with_scaler(mut self, scaler: scale::Scaler) -> Self {
+        self.scaler = scaler;
+        self
+    }
+
+    /// The layout of pixels to draw in. If the compositor doesn't support it, `Argb8888` is used
+    /// for formats with alpha and `Xrgb8888` for ones without. Check `FrameInfo::format` in
+    /// `WindowAble::draw_frame` for the one you got. Popups and subsurfaces use the format of the
+    /// window that opened them. Defaults to `PixelFormat::Argb8888`
+    pub fn with_pixel_format(mut self, format: PixelFormat) -> Self {
+        self.pixel_format = format;
+        self
+    }
+
+    /// Promise the compositor that every pixel is fully opaque, so it can skip blending the window
+    /// with what is behind it. Only use this if you always draw alpha as 0xFF
+    pub fn with_opaque(mut self, opaque: bool) -> Self {
+        self.opaque = opaque;
+        self
+    }
+
+    /// Tell the compositor which parts of the window are fully opaque, like `with_opaque` for only
+    /// part of the window. In window pixels, even with a virtual resolution
+    pub fn with_opaque_region(mut self, region: Vec<Rect>) -> Self {
+        self.opaque_region = region;
+        self
+    }
+
+    /// Only take mouse input inside these rectangles, so the rest of the window is click-through.
+    /// In window pixels, even with a virtual resolution
+    pub fn with_input_region(mut self, region: Vec<Rect>) -> Self {
+        self.input_region = Some(region);
+        self
+    }
+
+    /// How many buffers the window draws into. Defaults to `Buffering::Double`. Try
+    /// `Buffering::Triple` if `PoolStats::allocations` keeps going up while the window size stays
+    /// the same
+    pub fn with_buffering(mut self, buffering: Buffering) -> Self {
+        self.buffering = buffering;
+        self
+    }
+
+    /// Make the buffer passed to `draw` always hold the last frame, so only the parts that changed
+    /// need drawing. Report them with `WLibRequest::Damage` each frame, and wlib copies just those
+    /// forward into the next buffer. The buffer starts out transparent after the window resizes,
+    /// so redraw everything in `WindowAble::resized`. With a virtual resolution the app always
+    /// draws into the same buffer, so it is retained either way
+    pub fn with_retained(mut self, retained: bool) -> Self {
+        self.retained = retained;
+        self
+    }
+
+    /// Check every frame for pixels with a color larger than their alpha, which means it was drawn
+    /// with straight alpha instead of premultiplied. Prints a warning for the first one found.
+    /// Slow, so only meant for debugging
+    pub fn with_alpha_validation(mut self, validate: bool) -> Self {
+        self.validate_alpha = validate;
+        self
+    }
+
+    /// Sends the opaque and input regions to the compositor. They apply on the next commit
+    fn apply_regions(
+        &self,
+        surface: &wl_surface::WlSurface,
+        compositor: &CompositorState,
+        size: WindowSize,
+    ) {
+        let whole_window = [Rect {
+            x: 0,
+            y: 0,
+            width: size.width,
+            height: size.height,
+        }];
+        let opaque_region = if self.opaque {
+            &whole_window[..]
+        } else {
+            &self.opaque_region
+        };
+
+        let Ok(region) = Region::new(compositor) else {
+            return;
+        };
+        for rect in opaque_region {
+            region.add(rect.x, rect.y, rect.width as i32, rect.height as i32);
+        }
+        surface.set_opaque_region(Some(region.wl_region()));
+
+        match &self.input_region {
+            Some(input_region) => {
+                let Ok(region) = Region::new(compositor) else {
+                    return;
+                };
+                for rect in input_region {
+                    region.add(rect.x, rect.y, rect.width as i32, rect.height as i32);
+                }
+                surface.set_input_region(Some(region.wl_region()));
+            }
+            None => surface.set_input_region(None),
+        }
+    }
+}
+
+/// Where to place a popup opened with `WLibRequest::OpenPopup`, relative to the window that opened it.
+///
+/// The popup is lined up against `anchor_rect`: `anchor` picks the point on the rectangle, and
+/// `gravity` the direction the popup extends from that point. If that would put it off screen,
+/// the compositor moves it as allowed by `constraint_adjustment`.
+/// # Example
+/// ```rust
+/// // A context menu under the mouse
+/// fn context_menu(context: &wlib::Context) -> wlib::PopupSettings {
+///     let (x, y) = context.mouse_state.position;
+///     wlib::PopupSettings::new(wlib::WindowSize {
+///         width: 150,
+///         height: 200,
+///     })
+///     .with_anchor_rect(wlib::Rect {
+///         x: x as i32,
+///         y: y as i32,
+///         width: 1,
+///         height: 1,
+///     })
+///     .with_grab(true)
+/// }
+/// ```
+pub struct PopupSettings {
+    /// The size the popup asks for. The compositor can pick a different one
+    size: WindowSize,
+
+    /// The area of the parent the popup is placed against, for example the button that opened it
+    anchor_rect: Rect,
+
+    anchor: Anchor,
+
+    gravity: Gravity,
+
+    constraint_adjustment: ConstraintAdjustment,
+
+    /// Extra distance from the anchor point
+    offset: (i32, i32),
+
+    /// Take keyboard and pointer input until the popup closes
+    grab: bool,
+}
+
+impl PopupSettings {
+    /// A popup that opens below and to the right of the top left corner of its parent. Set
+    /// `with_anchor_rect` to place it somewhere useful. Width and height are at least 1
+    pub fn new(size: WindowSize) -> Self {
+        Self {
+            size: WindowSize {
+                width: size.width.clamp(1, MAX_REQUESTED_SIZE),
+                height: size.height.clamp(1, MAX_REQUESTED_SIZE),
+            },
+            anchor_rect: Rect {
+                x: 0,
+                y: 0,
+                width: 1,
+                height: 1,
+            },
+            anchor: Anchor::BottomLeft,
+            gravity: Gravity::BottomRight,
+            constraint_adjustment: ConstraintAdjustment::FlipY
+                | ConstraintAdjustment::SlideX
+                | ConstraintAdjustment::SlideY,
+            offset: (0, 0),
+            grab: false,
+        }
+    }
+
+    /// The area of the parent window to place the popup against, like the button that opened it
+    /// or a 1x1 rectangle at the mouse. Can't be empty, so width and height are at least 1. In
+    /// the same pixels as `Context::mouse_state`, so virtual pixels with a virtual resolution
+    pub fn with_anchor_rect(mut self, rect: Rect) -> Self {
+        self.anchor_rect = Rect {
+            width: rect.width.max(1),
+            height: rect.height.max(1),
+            ..rect
+        };
+        self
+    }
+
+    /// Which point of the anchor rectangle the popup is placed at. Defaults to `Anchor::BottomLeft`,
+    /// like a drop down menu
+    pub fn with_anchor(mut self, anchor: Anchor) -> Self {
+        self.anchor = anchor;
+        self
+    }
+
+    /// Which direction the popup extends from the anchor point. Defaults to `Gravity::BottomRight`
+    pub fn with_gravity(mut self, gravity: Gravity) -> Self {
+        self.gravity = gravity;
+        self
+    }
+
+    /// How the compositor may move the popup if it doesn't fit on screen. Defaults to flipping
+    /// it above the anchor, and sliding it along either axis
+    pub fn with_constraint_adjustment(mut self, adjustment: ConstraintAdjustment) -> Self {
+        self.constraint_adjustment = adjustment;
+        self
+    }
+
+    pub fn with_offset(mut self, x: i32, y: i32) -> Self {
+        self.offset = (x, y);
+        self
+    }
+
+    /// Send all keyboard and mouse input to the popup until it closes, and close it when the user
+    /// clicks outside of it. This is what menus usually want, tooltips don't.
+    ///
+    /// The compositor only allows a grab in response to a mouse press, so the popup must be opened
+    /// in the same frame as the press.
+    pub fn with_grab(mut self, grab: bool) -> Self {
+        self.grab = grab;
+        self
+    }
+}
+
+/// Settings for a layer opened with `WLibRequest::OpenSubsurface`
+pub struct SubsurfaceSettings {
+    /// Size of the subsurface buffer. Unlike windows this is entirely up to the app
+    size: WindowSize,
+
+    /// Relative to the top left of the parent
+    position: (i32, i32),
+
+    sync: bool,
+}
+
+impl SubsurfaceSettings {
+    /// Width and height are at least 1
+    pub fn new(size: WindowSize) -> Self {
+        Self {
+            size: WindowSize {
+                width: size.width.clamp(1, MAX_REQUESTED_SIZE),
+                height: size.height.clamp(1, MAX_REQUESTED_SIZE),
+            },
+            position: (0, 0),
+            sync: false,
+        }
+    }
+
+    /// Where the top left corner of the subsurface is, relative to the top left of the parent
+    pub fn with_position(mut self, x: i32, y: i32) -> Self {
+        self.position = (x, y);
+        self
+    }
+
+    /// In sync mode, each frame the subsurface draws is only shown once the parent draws its next
+    /// frame, so the two always match. Otherwise it is shown straight away, so it can update at
+    /// its own rate. Defaults to `false`.
+    ///
+    /// Position and stacking changes always wait for the parent's next frame.
+    pub fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+}
+
+/// Runs a struct implementing `WindowAble` by setting up a wayland event loop.
+pub fn run(state: Box<dyn WindowAble>, settings: WLibSettings) {
+    run_windows(vec![(state, settings)]);
+}
+
+/// Like `run`, but opens several windows sharing one wayland connection and event loop. Returns once
+/// every window has closed. More windows can be opened later with `WLibRequest::OpenWindow`.
+/// # Example
+/// ```rust
+/// struct Editor;
+/// struct Palette;
+///
+/// impl wlib::WindowAble for Editor {
+///     fn draw(&mut self, _buffer: &mut [u8], _frame: wlib::WindowSize) {}
+/// }
+///
+/// impl wlib::WindowAble for Palette {
+///     fn draw(&mut self, _buffer: &mut [u8], _frame: wlib::WindowSize) {}
+/// }
+///
+/// fn start() {
+///     wlib::run_windows(vec![
+///         (Box::new(Editor), wlib::WLibSettings::new().with_title("Level editor")),
+///         (Box::new(Palette), wlib::WLibSettings::new().with_title("Palette")),
+///     ]);
+/// }
+/// ```
+pub fn run_windows(windows: Vec<(Box<dyn WindowAble>, WLibSettings)>) {
+    if windows.is_empty() {
+        return;
+    }
+
+    // All Wayland apps start by connecting the compositor (server).
+    let conn = Connection::connect_to_env().unwrap();
+
+    // Enumerate the list of globals to get the protocols the server implements.
+    let (globals, event_queue) = registry_queue_init(&conn).unwrap();
+    let qh = event_queue.handle();
+    let mut event_loop: EventLoop<WindowManager> =
+        EventLoop::try_new().expect("Failed to initialize the event loop!");
+    let loop_handle = event_loop.handle();
+    WaylandSource::new(conn.clone(), event_queue)
+        .insert(loop_handle)
+        .unwrap();
+
+    // The compositor (not to be confused with the server which is commonly called the compositor) allows
+    // configuring surfaces to be presented.
+    let compositor = CompositorState::bind(&globals, &qh).expect("wl_compositor not available");
+    // For desktop platforms, the XDG shell is the standard protocol for creating desktop windows.
+    let xdg_shell = XdgShell::bind(&globals, &qh).expect("xdg shell is not available");
+
+    // Since we are not using the GPU in this example, we use wl_shm to allow software rendering to a buffer
+    // we share with the compositor process.
+    let shm = Shm::bind(&globals, &qh).expect("wl shm is not available.");
+    // If the compositor supports xdg-activation it probably wants us to use it to get focus
+    let xdg_activation = ActivationState::bind(&globals, &qh).ok();
+    // Used for client side decorations on compositors without server side ones
+    let subcompositor = SubcompositorState::bind(compositor.wl_compositor().clone(), &globals, &qh)
+        .ok()
+        .map(Arc::new);
+    // Used for `Scaler::Compositor`
+    let viewporter = globals.bind(&qh, 1..=1, ()).ok();
+
+    // We don't know how large the windows will be yet, so lets assume the sizes we would pick for
+    // the initial memory allocation, with room for every buffer each window draws into.
+    let pool_size: usize = windows
+        .iter()
+        .map(|(_, settings)| {
+            let size = settings.choose_size((None, None), settings.size, &WindowState::default());
+            size.width as usize * size.height as usize * 4 * settings.buffering.buffer_count()
+        })
+        .sum();
+    let pool = BufferPool::new(pool_size, &shm);
+
+    let mut window_manager = WindowManager {
+        // Seats and outputs may be hotplugged at runtime, therefore we need to setup a registry state to
+        // listen for seats and outputs.
+        registry_state: RegistryState::new(&globals),
+        seat_state: SeatState::new(&globals, &qh),
+        output_state: OutputState::new(&globals, &qh),
+        compositor,
+        xdg_shell,
+        shm,
+        xdg_activation,
+        subcompositor,
+        viewporter,
+
+        pool,
+        windows: Vec::new(),
+
+        keyboard: None,
+        pointer: None,
+        keyboard_focus: None,
+        frame_pointer_focus: None,
+        last_button_serial: None,
+
+        outputs: Vec::new(),
+        modifiers: Modifiers::default(),
+        keymap: None,
+    };
+
+    for (state, settings) in windows {
+        window_manager.open_window(state, settings, &qh);
+    }
+
+    // We don't draw immediately, the configure will notify us when to first draw.
+    while !window_manager.windows.is_empty() {
+        event_loop
+            .dispatch(Duration::ZERO, &mut window_manager)
+            .unwrap();
+
+        let open_windows = window_manager.windows.len();
+        window_manager.remove_closed_windows();
+        if window_manager.windows.len() < open_windows {
+            window_manager.trim_pool();
+        }
+    }
+}
 
 impl CompositorHandler for WindowManager {
     fn scale_factor_changed(
@@ -383,26 +1427,56 @@ impl CompositorHandler for WindowManager {
 
     fn frame(
         &mut self,
-        conn: &Connection,
+        _conn: &Connection,
         qh: &QueueHandle<Self>,
-        _surface: &wl_surface::WlSurface,
+        surface: &wl_surface::WlSurface,
         _time: u32,
     ) {
+        let Some(index) = self.window_index(surface) else {
+            return;
+        };
+        let pool_stats = self
+            .pool
+            .stats(self.windows.iter().map(|managed| &managed.buffers));
+        let managed = &mut self.windows[index];
+        managed.context.pool_stats = pool_stats;
+
         let now = std::time::Instant::now();
-        let delta = self
+        let delta = managed
             .last_frame_time
             .map(|last| now - last)
             .unwrap_or(Duration::ZERO);
 
-        self.last_frame_time = Some(now);
-        self.context.delta_time = delta;
+        managed.last_frame_time = Some(now);
+        managed.context.delta_time = delta;
 
-        let request = self.managed_window.update(self.context.clone());
-        self.handle_update(request);
-
-        self.draw(conn, qh);
+        let mut requests = Vec::new();
+        managed
+            .state
+            .update_requests(managed.context.clone(), &mut requests);
+        for request in requests {
+            self.handle_update(index, request, qh);
+        }
 
-        self.context.event_queue.clear();
+        let managed = &mut self.windows[index];
+        managed.draw(&mut self.pool, self.shm.formats(), &self.compositor, qh);
+
+        managed.context.event_queue.clear();
+        managed.context.keys_pressed_this_frame.clear();
+        managed.context.keys_released_this_frame.clear();
+        managed
+            .context
+            .mouse_state
+            .mouse_buttons_pressed_this_frame
+            .clear();
+        managed
+            .context
+            .mouse_state
+            .mouse_buttons_released_this_frame
+            .clear();
+
+        // Now that the frame is committed, the pool knows how much memory the windows need
+        self.trim_pool();
     }
 
     fn surface_enter(
@@ -435,61 +1509,199 @@ impl OutputHandler for WindowManager {
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _output: wl_output::WlOutput,
+        output: wl_output::WlOutput,
     ) {
+        self.outputs.push(Output {
+            info: self.output_state.info(&output),
+            wl_output: output,
+        });
+        self.update_outputs();
     }
 
     fn update_output(
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _output: wl_output::WlOutput,
+        output: wl_output::WlOutput,
     ) {
+        let info = self.output_state.info(&output);
+        if let Some(known) = self
+            .outputs
+            .iter_mut()
+            .find(|known| known.wl_output == output)
+        {
+            known.info = info;
+        }
+        self.update_outputs();
     }
 
     fn output_destroyed(
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _output: wl_output::WlOutput,
+        output: wl_output::WlOutput,
     ) {
+        self.outputs.retain(|known| known.wl_output != output);
+        self.update_outputs();
     }
 }
 
 impl WindowHandler for WindowManager {
-    fn request_close(&mut self, _: &Connection, _: &QueueHandle<Self>, _: &Window) {
-        self.context.close_requested = true;
+    fn request_close(&mut self, _: &Connection, _: &QueueHandle<Self>, window: &Window) {
+        if let Some(managed) = self
+            .windows
+            .iter_mut()
+            .find(|managed| matches!(&managed.role, SurfaceRole::Window(w) if w == window))
+        {
+            managed.close_requested();
+        }
     }
 
     fn configure(
         &mut self,
-        conn: &Connection,
+        _conn: &Connection,
         qh: &QueueHandle<Self>,
-        _window: &Window,
+        window: &Window,
         configure: WindowConfigure,
         _serial: u32,
     ) {
         // println!("Window configured to: {:?}", configure);
 
-        self.buffer = None;
+        let Some(managed) = self
+            .windows
+            .iter_mut()
+            .find(|managed| matches!(&managed.role, SurfaceRole::Window(w) if w == window))
+        else {
+            return;
+        };
 
-        if self.settings.window_static_size.is_none() {
-            self.width = configure.new_size.0.map(|v| v.get()).unwrap_or(256);
-            self.height = configure.new_size.1.map(|v| v.get()).unwrap_or(256);
+        let old_size = managed.context.window_size;
+
+        // When we draw our own decorations, the size the compositor asks for includes them
+        let mut new_size = configure.new_size;
+        if configure.decoration_mode == DecorationMode::Client
+            && managed.settings.decorations.is_some()
+            && let Some(subcompositor) = self.subcompositor.clone()
+        {
+            let window_frame = managed.window_frame.get_or_insert_with(|| {
+                FallbackFrame::new(window, &self.shm, subcompositor, qh.clone())
+                    .expect("failed to create client side decorations frame.")
+            });
+            window_frame.set_hidden(false);
+            window_frame.update_state(configure.state);
+            window_frame.update_wm_capabilities(configure.capabilities);
+
+            if let (Some(width), Some(height)) = new_size {
+                new_size = window_frame.subtract_borders(width, height);
+            }
+        } else if let Some(window_frame) = managed.window_frame.as_mut() {
+            window_frame.set_hidden(true);
         }
 
-        self.context.window_size = WindowSize {
-            width: self.width,
-            height: self.height,
-        };
+        let window_state = WindowState::from_configure(&configure);
+        let size = managed.settings.choose_size(
+            (new_size.0.map(|v| v.get()), new_size.1.map(|v| v.get())),
+            WindowSize {
+                width: managed.width,
+                height: managed.height,
+            },
+            &window_state,
+        );
+        managed.width = size.width;
+        managed.height = size.height;
+        managed.context.window_size = managed.app_size();
+        managed.update_window_geometry();
+
+        if !managed.first_configure && old_size != managed.context.window_size {
+            managed.state.resized(old_size, managed.context.window_size);
+        }
+
+        let bounds = configure
+            .suggested_bounds
+            .map(|(width, height)| WindowSize { width, height });
+
+        if window_state != managed.context.window_state
+            || bounds != managed.context.bounds
+            || configure.decoration_mode != managed.context.decoration_mode
+        {
+            if window_state.activated != managed.context.window_state.activated {
+                managed
+                    .context
+                    .event_queue
+                    .push(Event::ActivatedChanged(window_state.activated));
+            }
+
+            managed.context.window_state = window_state;
+            managed.context.bounds = bounds;
+            managed.context.decoration_mode = configure.decoration_mode;
+            managed.context.event_queue.push(Event::WindowStateChanged);
+        }
 
         // self.width = configure.new_size.0.map(|v| v.get()).unwrap();
         // self.height = configure.new_size.1.map(|v| v.get()).unwrap();
 
         // Initiate the first draw.
-        if self.first_configure {
-            self.first_configure = false;
-            self.draw(conn, qh);
+        if managed.first_configure {
+            managed.first_configure = false;
+            managed.state.init(&managed.context);
+            managed.draw(&mut self.pool, self.shm.formats(), &self.compositor, qh);
+        }
+    }
+}
+
+impl PopupHandler for WindowManager {
+    fn configure(
+        &mut self,
+        _conn: &Connection,
+        qh: &QueueHandle<Self>,
+        popup: &Popup,
+        config: PopupConfigure,
+    ) {
+        let Some(managed) = self.windows.iter_mut().find(
+            |managed| matches!(&managed.role, SurfaceRole::Popup { popup: p, .. } if p == popup),
+        ) else {
+            return;
+        };
+
+        let old_size = managed.context.window_size;
+        let size = WindowSize {
+            width: config.width.max(1) as u32,
+            height: config.height.max(1) as u32,
+        };
+        managed.width = size.width;
+        managed.height = size.height;
+        managed.context.window_size = size;
+        managed.update_window_geometry();
+
+        if !managed.first_configure && old_size != size {
+            managed.state.resized(old_size, size);
+        }
+
+        // Initiate the first draw.
+        if managed.first_configure {
+            managed.first_configure = false;
+            managed.state.init(&managed.context);
+            managed.draw(&mut self.pool, self.shm.formats(), &self.compositor, qh);
+        }
+    }
+
+    fn done(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, popup: &Popup) {
+        let Some(managed) = self.windows.iter_mut().find(
+            |managed| matches!(&managed.role, SurfaceRole::Popup { popup: p, .. } if p == popup),
+        ) else {
+            return;
+        };
+        managed.close_accepted = true;
+
+        let SurfaceRole::Popup { parent, .. } = &managed.role else {
+            return;
+        };
+        let parent = parent.clone();
+        if let Some(index) = self.window_index(&parent) {
+            self.windows[index]
+                .context
+                .event_queue
+                .push(Event::PopupDismissed);
         }
     }
 }
@@ -497,11 +1709,13 @@ impl WindowHandler for WindowManager {
 impl ActivationHandler for WindowManager {
     type RequestData = RequestData;
 
-    fn new_token(&mut self, token: String, _data: &Self::RequestData) {
-        self.xdg_activation
-            .as_ref()
-            .unwrap()
-            .activate::<WindowManager>(self.window.wl_surface(), token);
+    fn new_token(&mut self, token: String, data: &Self::RequestData) {
+        if let Some(surface) = data.surface.as_ref() {
+            self.xdg_activation
+                .as_ref()
+                .unwrap()
+                .activate::<WindowManager>(surface, token);
+        }
     }
 }
 
@@ -571,12 +1785,24 @@ impl KeyboardHandler for WindowManager {
         raw: &[u32],
         keysyms: &[Keysym],
     ) {
-        if self.window.wl_surface() == surface {
-            // println!("Keyboard focus on window with pressed syms: {keysyms:?}");
-            self.keyboard_focus = true;
-            for (rawk, sym) in raw.iter().zip(keysyms.iter()) {
-                self.context.pressed_keys.insert(*rawk, *sym);
-            }
+        let Some(index) = self.window_index(surface) else {
+            return;
+        };
+        self.keyboard_focus = Some(surface.clone());
+
+        // println!("Keyboard focus on window with pressed syms: {keysyms:?}");
+        let managed = &mut self.windows[index];
+        managed.context.has_keyboard_focus = true;
+        managed
+            .context
+            .event_queue
+            .push(Event::KeyboardFocusChanged(true));
+        managed.state.focus_changed(true);
+        for (rawk, sym) in raw.iter().zip(keysyms.iter()) {
+            managed
+                .context
+                .pressed_keys
+                .insert(keys::Key::from(*rawk), *sym);
         }
     }
 
@@ -588,11 +1814,21 @@ impl KeyboardHandler for WindowManager {
         surface: &wl_surface::WlSurface,
         _: u32,
     ) {
-        if self.window.wl_surface() == surface {
-            // println!("Release keyboard focus on window");
-            self.keyboard_focus = false;
-            self.context.pressed_keys.clear();
-        }
+        let Some(index) = self.window_index(surface) else {
+            return;
+        };
+        self.keyboard_focus = None;
+
+        // println!("Release keyboard focus on window");
+        let managed = &mut self.windows[index];
+        managed.context.has_keyboard_focus = false;
+        managed
+            .context
+            .event_queue
+            .push(Event::KeyboardFocusChanged(false));
+        managed.state.focus_changed(false);
+        let held = std::mem::take(&mut managed.context.pressed_keys);
+        managed.context.keys_released_this_frame.extend(held);
     }
 
     fn press_key(
@@ -603,13 +1839,23 @@ impl KeyboardHandler for WindowManager {
         _: u32,
         event: KeyEvent,
     ) {
-        self.context
+        let Some(managed) = self.focused_window() else {
+            return;
+        };
+
+        managed
+            .context
             .event_queue
             .push(Event::KeyPress(event.clone()));
 
-        self.context
+        managed
+            .context
             .pressed_keys
-            .insert(event.raw_code, event.keysym);
+            .insert(keys::Key::from(event.raw_code), event.keysym);
+        managed
+            .context
+            .keys_pressed_this_frame
+            .insert(keys::Key::from(event.raw_code), event.keysym);
     }
 
     fn release_key(
@@ -620,11 +1866,23 @@ impl KeyboardHandler for WindowManager {
         _: u32,
         event: KeyEvent,
     ) {
-        self.context
+        let Some(managed) = self.focused_window() else {
+            return;
+        };
+
+        managed
+            .context
             .event_queue
             .push(Event::KeyRelease(event.clone()));
 
-        self.context.pressed_keys.remove(&event.raw_code);
+        managed
+            .context
+            .pressed_keys
+            .remove(&keys::Key::from(event.raw_code));
+        managed
+            .context
+            .keys_released_this_frame
+            .insert(keys::Key::from(event.raw_code), event.keysym);
     }
 
     fn repeat_key(
@@ -644,11 +1902,32 @@ impl KeyboardHandler for WindowManager {
         _: &QueueHandle<Self>,
         _: &wl_keyboard::WlKeyboard,
         _serial: u32,
-        _modifiers: Modifiers,
-        _raw_modifiers: RawModifiers,
-        _layout: u32,
+        modifiers: Modifiers,
+        raw_modifiers: RawModifiers,
+        layout: u32,
     ) {
         // println!("Update modifiers: {modifiers:?}");
+        self.modifiers = modifiers;
+        if let Some(keymap) = self.keymap.as_mut() {
+            keymap.set_modifiers(raw_modifiers, layout);
+        }
+        for managed in &mut self.windows {
+            managed.context.modifiers = modifiers;
+            managed.context.keymap = self.keymap.clone();
+        }
+    }
+
+    fn update_keymap(
+        &mut self,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+        _: &wl_keyboard::WlKeyboard,
+        keymap: keyboard::Keymap<'_>,
+    ) {
+        self.keymap = keys::Keymap::from_string(keymap.as_string());
+        for managed in &mut self.windows {
+            managed.context.keymap = self.keymap.clone();
+        }
     }
 }
 
@@ -657,19 +1936,38 @@ impl PointerHandler for WindowManager {
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _pointer: &wl_pointer::WlPointer,
+        pointer: &wl_pointer::WlPointer,
         events: &[PointerEvent],
     ) {
         for event in events {
-            // Ignore events for other surfaces
-            if &event.surface != self.window.wl_surface() {
+            // Events for other surfaces are for our title bars
+            let Some(index) = self.window_index(&event.surface) else {
+                self.frame_pointer_event(pointer, event);
                 continue;
+            };
+
+            // Give the app positions in virtual pixels
+            let mut event = event.clone();
+            if let Some(content) = self.windows[index].virtual_content_rect() {
+                event.position =
+                    scale::to_virtual(event.position, content, self.windows[index].app_size());
             }
 
+            // Pushed after the raw event so the press is seen before the click it produced etc
+            let mut derived_event = None;
+
+            if let Some(serial) = press_serial(&event.kind)
+                && let Some(data) = pointer.data::<PointerData>()
+            {
+                self.last_button_serial = Some((data.seat().clone(), serial));
+            }
+
+            let managed = &mut self.windows[index];
+
             use PointerEventKind as PEK;
             match event.kind {
                 PEK::Press {
-                    time: _,
+                    time,
                     button: b,
                     serial: _,
                 } => {
@@ -677,29 +1975,64 @@ impl PointerHandler for WindowManager {
                     // https://wayland.app/protocols/wayland#wl_pointer:event:button
                     // println!("button press: {b}");
                     if let Ok(bttn) = MouseButton::try_from(b) {
-                        self.context.mouse_state.mouse_buttons_pressed.insert(bttn);
+                        let count = managed.click_counter.press(
+                            bttn.clone(),
+                            time,
+                            event.position,
+                            managed.settings.double_click_interval,
+                            managed.settings.double_click_distance,
+                        );
+                        derived_event = Some(Event::Click {
+                            button: bttn.clone(),
+                            position: event.position,
+                            count,
+                        });
+
+                        managed
+                            .context
+                            .mouse_state
+                            .mouse_buttons_pressed_this_frame
+                            .insert(bttn.clone());
+                        managed
+                            .context
+                            .mouse_state
+                            .mouse_buttons_pressed
+                            .insert(bttn);
                     }
                 }
                 PEK::Release { button: b, .. } => {
                     // println!("button press: {b}");
                     if let Ok(bttn) = MouseButton::try_from(b) {
-                        self.context.mouse_state.mouse_buttons_pressed.remove(&bttn);
+                        managed
+                            .context
+                            .mouse_state
+                            .mouse_buttons_pressed
+                            .remove(&bttn);
+                        managed
+                            .context
+                            .mouse_state
+                            .mouse_buttons_released_this_frame
+                            .insert(bttn);
                     }
                 }
                 PEK::Enter { .. } => {
-                    self.context.is_window_focused = true;
+                    managed.context.set_pointer_inside(true);
+                    derived_event = Some(Event::PointerInsideChanged(true));
                 }
                 PEK::Leave { .. } => {
-                    self.context.is_window_focused = false;
+                    managed.context.set_pointer_inside(false);
+                    derived_event = Some(Event::PointerInsideChanged(false));
                 }
                 _ => {}
             }
 
-            self.context.mouse_state.position = event.position;
+            managed.context.mouse_state.position = event.position;
 
-            self.context
+            managed
+                .context
                 .event_queue
                 .push(Event::PointerEvent(event.clone()));
+            managed.context.event_queue.extend(derived_event);
         }
     }
 }
@@ -711,76 +2044,770 @@ impl ShmHandler for WindowManager {
 }
 
 impl WindowManager {
-    pub fn draw(&mut self, _conn: &Connection, qh: &QueueHandle<Self>) {
-        let width = self.width;
-        let height = self.height;
-        let stride = self.width as i32 * 4;
-
-        let buffer = self.buffer.get_or_insert_with(|| {
-            self.pool
-                .create_buffer(
-                    width as i32,
-                    height as i32,
-                    stride,
-                    wl_shm::Format::Argb8888,
-                )
-                .expect("create buffer")
-                .0
-        });
-        let canvas = match self.pool.canvas(buffer) {
-            Some(canvas) => canvas,
-            None => {
-                // This should be rare, but if the compositor has not released the previous
-                // buffer, we need double-buffering.
-                let (second_buffer, canvas) = self
-                    .pool
-                    .create_buffer(
-                        self.width as i32,
-                        self.height as i32,
-                        stride,
-                        wl_shm::Format::Argb8888,
-                    )
-                    .expect("create buffer");
-                *buffer = second_buffer;
-                canvas
+    /// Creates a window for `state`. It is drawn once the compositor sends its first configure
+    fn open_window(
+        &mut self,
+        state: Box<dyn WindowAble>,
+        settings: WLibSettings,
+        qh: &QueueHandle<Self>,
+    ) {
+        // A window is created from a surface.
+        let surface = self.compositor.create_surface(qh);
+
+        // And then we can create the window.
+        let decorations = match settings.decorations {
+            Some(DecorationMode::Server) => WindowDecorations::RequestServer,
+            Some(DecorationMode::Client) | None => WindowDecorations::ClientOnly,
+        };
+        let window = self.xdg_shell.create_window(surface, decorations, qh);
+
+        // Configure the window, this may include hints to the compositor about the desired minimum size of the
+        // window, app id for WM identification, the window title, etc.
+        window.set_title(&settings.window_title);
+
+        // GitHub does not let projects use the `org.github` domain but the `io.github` domain is fine.
+        window.set_app_id(&settings.app_id);
+
+        if settings.fullscreen {
+            window.set_fullscreen(None);
+        }
+
+        // In order for the window to be mapped, we need to perform an initial commit with no attached buffer.
+        // For more info, see WaylandSurface::commit
+        //
+        // The compositor will respond with an initial configure that we can then use to present to the window with
+        // the correct options.
+        window.commit();
+
+        // To request focus, we first need to request a token
+        if let Some(activation) = self.xdg_activation.as_ref() {
+            activation.request_token(
+                qh,
+                RequestData {
+                    seat_and_serial: None,
+                    surface: Some(window.wl_surface().clone()),
+                    app_id: Some(String::from(
+                        "io.github.smithay.client-toolkit.SimpleWindow",
+                    )),
+                },
+            )
+        }
+
+        // If both min and max size are set to the same value, it means the size is static.
+        // from (niri docs)[https://github.com/YaLTeR/niri/wiki/Floating-Windows], if this is the
+        // case the window is set to be floating in tiling window managers
+        window.set_min_size(settings.min_size.map(|size| (size.width, size.height)));
+        window.set_max_size(settings.max_size.map(|size| (size.width, size.height)));
+
+        let viewport = match (&self.viewporter, settings.virtual_resolution) {
+            (Some(viewporter), Some(_)) if settings.scaler == scale::Scaler::Compositor => {
+                Some(viewporter.get_viewport(window.wl_surface(), qh, ()))
             }
+            _ => None,
         };
 
-        // Draw to the window:
-        self.managed_window.draw(
-            canvas,
-            WindowSize {
-                width: self.width,
-                height: self.height,
+        let size = settings.choose_size((None, None), settings.size, &WindowState::default());
+        let mut managed =
+            self.new_managed_window(SurfaceRole::Window(window), size, state, settings);
+        managed.viewport = viewport;
+        self.windows.push(managed);
+    }
+
+    /// Opens a popup for `state`, placed relative to the window at `parent_index`
+    fn open_popup(
+        &mut self,
+        parent_index: usize,
+        state: Box<dyn WindowAble>,
+        settings: PopupSettings,
+        qh: &QueueHandle<Self>,
+    ) {
+        let Ok(positioner) = XdgPositioner::new(&self.xdg_shell) else {
+            return;
+        };
+        let parent = &self.windows[parent_index];
+
+        // Positions are relative to the window geometry, which doesn't start at the top left
+        // of the surface when we draw our own title bar
+        let (geometry_x, geometry_y) = match parent.window_frame.as_ref() {
+            Some(window_frame) if !window_frame.is_hidden() => window_frame.location(),
+            _ => (0, 0),
+        };
+        // The anchor rect is in the parent's virtual pixels, the positioner wants window pixels
+        let rect = match parent.virtual_content_rect() {
+            Some(content) => {
+                scale::rect_from_virtual(settings.anchor_rect, content, parent.app_size())
+            }
+            None => settings.anchor_rect,
+        };
+        positioner.set_size(settings.size.width as i32, settings.size.height as i32);
+        positioner.set_anchor_rect(
+            rect.x - geometry_x,
+            rect.y - geometry_y,
+            rect.width as i32,
+            rect.height as i32,
+        );
+        positioner.set_anchor(settings.anchor);
+        positioner.set_gravity(settings.gravity);
+        positioner.set_constraint_adjustment(settings.constraint_adjustment);
+        positioner.set_offset(settings.offset.0, settings.offset.1);
+
+        let Some(parent_xdg_surface) = parent.role.xdg_surface() else {
+            return;
+        };
+        let Ok(popup) = Popup::new(
+            parent_xdg_surface,
+            &positioner,
+            qh,
+            &self.compositor,
+            &self.xdg_shell,
+        ) else {
+            return;
+        };
+
+        if settings.grab
+            && let Some((seat, serial)) = &self.last_button_serial
+        {
+            popup.xdg_popup().grab(seat, *serial);
+        }
+
+        let window_settings = WLibSettings::new()
+            .with_double_click_interval(parent.settings.double_click_interval)
+            .with_double_click_distance(parent.settings.double_click_distance)
+            .with_buffering(parent.settings.buffering)
+            .with_pixel_format(parent.settings.pixel_format)
+            .with_alpha_validation(parent.settings.validate_alpha);
+        let role = SurfaceRole::Popup {
+            popup,
+            parent: parent.role.wl_surface().clone(),
+        };
+        let managed = self.new_managed_window(role, settings.size, state, window_settings);
+        self.windows.push(managed);
+    }
+
+    /// Opens a subsurface for `state` on top of the window at `parent_index`
+    fn open_subsurface(
+        &mut self,
+        parent_index: usize,
+        state: Box<dyn WindowAble>,
+        settings: SubsurfaceSettings,
+        qh: &QueueHandle<Self>,
+    ) {
+        let Some(subcompositor) = self.subcompositor.as_ref() else {
+            return;
+        };
+        let parent = &self.windows[parent_index];
+        let parent_surface = parent.role.wl_surface().clone();
+
+        let (subsurface, surface) = subcompositor.create_subsurface(parent_surface.clone(), qh);
+        subsurface.set_position(settings.position.0, settings.position.1);
+        if !settings.sync {
+            subsurface.set_desync();
+        }
+
+        let window_settings = WLibSettings::new()
+            .with_size(settings.size)
+            .with_double_click_interval(parent.settings.double_click_interval)
+            .with_double_click_distance(parent.settings.double_click_distance)
+            .with_buffering(parent.settings.buffering)
+            .with_pixel_format(parent.settings.pixel_format)
+            .with_alpha_validation(parent.settings.validate_alpha);
+        let role = SurfaceRole::Subsurface {
+            subsurface,
+            surface,
+            parent: parent_surface,
+        };
+        let mut managed = self.new_managed_window(role, settings.size, state, window_settings);
+
+        // Subsurfaces don't get configured, so start drawing straight away
+        managed.first_configure = false;
+        managed.context.window_size = settings.size;
+        managed.state.init(&managed.context);
+        managed.draw(&mut self.pool, self.shm.formats(), &self.compositor, qh);
+        self.windows.push(managed);
+    }
+
+    fn new_managed_window(
+        &self,
+        role: SurfaceRole,
+        size: WindowSize,
+        state: Box<dyn WindowAble>,
+        settings: WLibSettings,
+    ) -> ManagedWindow {
+        ManagedWindow {
+            close_accepted: false,
+            first_configure: true,
+            width: size.width,
+            height: size.height,
+            buffers: BufferRing::default(),
+            role,
+            window_frame: None,
+            last_frame_time: None,
+            click_counter: ClickCounter::default(),
+            virtual_buffer: Vec::new(),
+            viewport: None,
+            regions_dirty: true,
+            invalid_alpha_reported: false,
+            damage: Vec::new(),
+            retained: RetainedFrame::default(),
+
+            state,
+            context: Context {
+                delta_time: std::time::Duration::from_millis(0),
+                pressed_keys: HashMap::new(),
+                keys_pressed_this_frame: HashMap::new(),
+                keys_released_this_frame: HashMap::new(),
+                modifiers: self.modifiers,
+                keymap: self.keymap.clone(),
+                close_requested: false,
+                event_queue: Vec::new(),
+                has_keyboard_focus: false,
+                is_pointer_inside: false,
+                #[allow(deprecated)]
+                is_window_focused: false,
+
+                mouse_state: MouseState {
+                    position: (0.0, 0.0),
+                    mouse_buttons_pressed: HashSet::new(),
+                    mouse_buttons_pressed_this_frame: HashSet::new(),
+                    mouse_buttons_released_this_frame: HashSet::new(),
+                },
+                window_size: WindowSize {
+                    height: 0,
+                    width: 0,
+                },
+                outputs: self.outputs.clone(),
+                window_state: WindowState::default(),
+                bounds: None,
+                decoration_mode: DecorationMode::Client,
+                pool_stats: PoolStats::default(),
+            },
+            settings,
+        }
+    }
+
+    /// Shrinks the pool if windows got smaller or closed
+    fn trim_pool(&mut self) {
+        let rings: Vec<&BufferRing> = self
+            .windows
+            .iter()
+            .map(|managed| &managed.buffers)
+            .collect();
+        self.pool.trim(&self.shm, &rings);
+    }
+
+    /// Drops the windows whose app accepted a close request, along with any popups and subsurfaces
+    /// they opened
+    fn remove_closed_windows(&mut self) {
+        loop {
+            let closing: Vec<wl_surface::WlSurface> = self
+                .windows
+                .iter()
+                .filter(|managed| managed.close_accepted)
+                .map(|managed| managed.role.wl_surface().clone())
+                .collect();
+
+            let mut closed_popup = false;
+            for managed in &mut self.windows {
+                if let Some(parent) = managed.role.parent()
+                    && !managed.close_accepted
+                    && closing.contains(parent)
+                {
+                    managed.close_accepted = true;
+                    closed_popup = true;
+                }
+            }
+            if !closed_popup {
+                break;
+            }
+        }
+
+        let (closed, open): (Vec<_>, Vec<_>) = std::mem::take(&mut self.windows)
+            .into_iter()
+            .partition(|managed| managed.close_accepted);
+        self.windows = open;
+
+        // Children are always opened after their parent, so this destroys them before it
+        for mut managed in closed.into_iter().rev() {
+            managed.state.exiting();
+            if let Some(viewport) = &managed.viewport {
+                viewport.destroy();
+            }
+            if let SurfaceRole::Subsurface {
+                subsurface,
+                surface,
+                ..
+            } = &managed.role
+            {
+                subsurface.destroy();
+                surface.destroy();
+            }
+        }
+    }
+
+    fn window_index(&self, surface: &wl_surface::WlSurface) -> Option<usize> {
+        self.windows
+            .iter()
+            .position(|managed| managed.role.wl_surface() == surface)
+    }
+
+    /// The window that key presses go to
+    fn focused_window(&mut self) -> Option<&mut ManagedWindow> {
+        let surface = self.keyboard_focus.as_ref()?;
+        self.windows
+            .iter_mut()
+            .find(|managed| managed.role.wl_surface() == surface)
+    }
+
+    fn update_outputs(&mut self) {
+        for managed in &mut self.windows {
+            managed.context.outputs = self.outputs.clone();
+        }
+    }
+
+    /// Pointer events on the title bar and borders of client side decorations
+    fn frame_pointer_event(&mut self, pointer: &wl_pointer::WlPointer, event: &PointerEvent) {
+        let (x, y) = event.position;
+        use PointerEventKind as PEK;
+
+        // Find which window the title bar belongs to. Frames ignore surfaces that aren't theirs
+        if let PEK::Enter { .. } = event.kind {
+            self.frame_pointer_focus = self.windows.iter_mut().find_map(|managed| {
+                managed.window_frame.as_mut()?.click_point_moved(
+                    Duration::ZERO,
+                    &event.surface.id(),
+                    x,
+                    y,
+                )?;
+                Some(managed.role.wl_surface().clone())
+            });
+        }
+
+        let Some(index) = self
+            .frame_pointer_focus
+            .as_ref()
+            .and_then(|surface| self.window_index(surface))
+        else {
+            return;
+        };
+        let managed = &mut self.windows[index];
+        let Some(window_frame) = managed.window_frame.as_mut() else {
+            return;
+        };
+
+        let action = match event.kind {
+            PEK::Enter { .. } => None,
+            PEK::Motion { time } => {
+                window_frame.click_point_moved(
+                    Duration::from_millis(time as u64),
+                    &event.surface.id(),
+                    x,
+                    y,
+                );
+                None
+            }
+            PEK::Leave { .. } => {
+                window_frame.click_point_left();
+                self.frame_pointer_focus = None;
+                None
+            }
+            PEK::Press {
+                button,
+                serial,
+                time,
+            }
+            | PEK::Release {
+                button,
+                serial,
+                time,
+            } => {
+                let pressed = matches!(event.kind, PEK::Press { .. });
+                let click = match MouseButton::try_from(button) {
+                    Ok(MouseButton::BtnLeft) => FrameClick::Normal,
+                    Ok(MouseButton::BtnRight) => FrameClick::Alternate,
+                    _ => return,
+                };
+                window_frame
+                    .on_click(Duration::from_millis(time as u64), click, pressed)
+                    .map(|action| (action, serial))
+            }
+            PEK::Axis { .. } => None,
+        };
+
+        let Some((action, serial)) = action else {
+            return;
+        };
+        let Some(seat) = pointer
+            .data::<PointerData>()
+            .map(|data| data.seat().clone())
+        else {
+            return;
+        };
+
+        let SurfaceRole::Window(window) = &managed.role else {
+            return;
+        };
+        match action {
+            // Let the app confirm, same as when the compositor asks
+            FrameAction::Close => managed.close_requested(),
+            FrameAction::Minimize => window.set_minimized(),
+            FrameAction::Maximize => window.set_maximized(),
+            FrameAction::UnMaximize => window.unset_maximized(),
+            FrameAction::ShowMenu(x, y) => window.show_window_menu(&seat, serial, (x, y)),
+            FrameAction::Move => window.move_(&seat, serial),
+            FrameAction::Resize(edge) => {
+                let edge = match edge {
+                    FrameResizeEdge::Top => ResizeEdge::Top,
+                    FrameResizeEdge::Bottom => ResizeEdge::Bottom,
+                    FrameResizeEdge::Left => ResizeEdge::Left,
+                    FrameResizeEdge::TopLeft => ResizeEdge::TopLeft,
+                    FrameResizeEdge::BottomLeft => ResizeEdge::BottomLeft,
+                    FrameResizeEdge::Right => ResizeEdge::Right,
+                    FrameResizeEdge::TopRight => ResizeEdge::TopRight,
+                    FrameResizeEdge::BottomRight => ResizeEdge::BottomRight,
+                    _ => ResizeEdge::None,
+                };
+                window.resize(&seat, serial, edge);
+            }
+            _ => {}
+        }
+    }
+
+    /// Carries out a request from the app in the window at `index`
+    fn handle_update(&mut self, index: usize, request: WLibRequest, qh: &QueueHandle<Self>) {
+        let request = match request {
+            WLibRequest::CloseAccepted => {
+                self.windows[index].close_accepted = true;
+                return;
+            }
+            WLibRequest::OpenWindow(state, settings) => {
+                self.open_window(state, settings, qh);
+                return;
+            }
+            WLibRequest::OpenPopup(state, settings) => {
+                self.open_popup(index, state, settings, qh);
+                return;
+            }
+            WLibRequest::SetOpaque(opaque) => {
+                let managed = &mut self.windows[index];
+                managed.settings.opaque = opaque;
+                managed.regions_dirty = true;
+                return;
+            }
+            WLibRequest::SetOpaqueRegion(region) => {
+                let managed = &mut self.windows[index];
+                managed.settings.opaque_region = region;
+                managed.regions_dirty = true;
+                return;
+            }
+            WLibRequest::SetInputRegion(region) => {
+                let managed = &mut self.windows[index];
+                managed.settings.input_region = region;
+                managed.regions_dirty = true;
+                return;
+            }
+            WLibRequest::Damage(rect) => {
+                self.windows[index].damage.push(rect);
+                return;
+            }
+            WLibRequest::OpenSubsurface(state, settings) => {
+                self.open_subsurface(index, state, settings, qh);
+                return;
+            }
+            request => request,
+        };
+
+        let managed = &mut self.windows[index];
+        let window = match &managed.role {
+            SurfaceRole::Window(window) => window.clone(),
+            SurfaceRole::Subsurface {
+                subsurface, parent, ..
+            } => {
+                match request {
+                    WLibRequest::RequestSize(size) => managed.request_size(size),
+                    WLibRequest::SetPosition(x, y) => subsurface.set_position(x, y),
+                    WLibRequest::PlaceAboveParent => subsurface.place_above(parent),
+                    WLibRequest::PlaceBelowParent => subsurface.place_below(parent),
+                    WLibRequest::SetSync(true) => subsurface.set_sync(),
+                    WLibRequest::SetSync(false) => subsurface.set_desync(),
+                    // The rest only make sense for top level windows
+                    _ => {}
+                }
+                return;
+            }
+            SurfaceRole::Popup { .. } => return,
+        };
+
+        match request {
+            WLibRequest::SetTitle(title) => window.set_title(title),
+            WLibRequest::SetAppId(id) => window.set_app_id(id),
+            WLibRequest::SetMinSize(size) => {
+                managed.settings.min_size = size;
+                window.set_min_size(size.map(|size| (size.width, size.height)));
+            }
+            WLibRequest::SetMaxSize(size) => {
+                managed.settings.max_size = size;
+                window.set_max_size(size.map(|size| (size.width, size.height)));
+            }
+            WLibRequest::RequestSize(size) => managed.request_size(size),
+            WLibRequest::SetFullscreen(output) => window.set_fullscreen(output.as_ref()),
+            WLibRequest::UnsetFullscreen => window.unset_fullscreen(),
+            WLibRequest::Maximize => window.set_maximized(),
+            WLibRequest::Unmaximize => window.unset_maximized(),
+            WLibRequest::Minimize => window.set_minimized(),
+            WLibRequest::BeginMove => {
+                if let Some((seat, serial)) = &self.last_button_serial {
+                    window.move_(seat, *serial);
+                }
+            }
+            WLibRequest::BeginResize(edge) => {
+                if let Some((seat, serial)) = &self.last_button_serial {
+                    window.resize(seat, *serial, edge);
+                }
+            }
+            WLibRequest::ShowWindowMenu => {
+                if let Some((seat, serial)) = &self.last_button_serial {
+                    // The mouse position is in virtual pixels, the menu is placed in window pixels
+                    let mut position = managed.context.mouse_state.position;
+                    if let Some(content) = managed.virtual_content_rect() {
+                        position = scale::from_virtual(position, content, managed.app_size());
+                    }
+                    window.show_window_menu(seat, *serial, (position.0 as i32, position.1 as i32));
+                }
+            }
+            // Only for subsurfaces
+            WLibRequest::SetPosition(..)
+            | WLibRequest::PlaceAboveParent
+            | WLibRequest::PlaceBelowParent
+            | WLibRequest::SetSync(_) => {}
+            // Handled above
+            WLibRequest::CloseAccepted
+            | WLibRequest::OpenWindow(..)
+            | WLibRequest::OpenPopup(..)
+            | WLibRequest::OpenSubsurface(..)
+            | WLibRequest::SetOpaque(_)
+            | WLibRequest::SetOpaqueRegion(_)
+            | WLibRequest::SetInputRegion(_)
+            | WLibRequest::Damage(_) => {}
+        }
+    }
+}
+
+impl ManagedWindow {
+    fn draw(
+        &mut self,
+        pool: &mut BufferPool,
+        formats: &[wl_shm::Format],
+        compositor: &CompositorState,
+        qh: &QueueHandle<WindowManager>,
+    ) {
+        let format = self.settings.pixel_format.or_supported(formats);
+        let bytes_per_pixel = format.bytes_per_pixel();
+        let app_size = self.app_size();
+        let window_size = WindowSize {
+            width: self.width,
+            height: self.height,
+        };
+        let content = self.virtual_content_rect();
+
+        // When the compositor scales, the buffer is in virtual pixels
+        let layout = content
+            .filter(|_| self.viewport.is_some())
+            .map(|content| scale::ViewportLayout::new(content, app_size, window_size));
+        let WindowSize { width, height } = layout.map_or(window_size, |layout| layout.buffer_size);
+        let stride = (width * bytes_per_pixel) as i32;
+
+        let (buffer, canvas, age) = self.buffers.acquire(
+            pool,
+            BufferLayout {
+                width: width as i32,
+                height: height as i32,
+                stride,
+                format: format.wl_format(),
             },
+            self.settings.buffering,
         );
 
-        // Damage the entire window
-        self.window
-            .wl_surface()
-            .damage_buffer(0, 0, self.width as i32, self.height as i32);
+        // Draw our title bar, if the compositor doesn't
+        if let Some(window_frame) = self.window_frame.as_mut()
+            && window_frame.is_dirty()
+            && !window_frame.is_hidden()
+        {
+            window_frame.draw();
+        }
+
+        let frame_info = FrameInfo {
+            size: app_size,
+            stride: app_size.width * bytes_per_pixel,
+            format,
+        };
+        let bytes_per_pixel = bytes_per_pixel as usize;
+
+        // Partial damage only works when the buffer is in app pixels and the compositor has seen
+        // this size before
+        let damage = std::mem::take(&mut self.damage);
+        let damage = (!damage.is_empty() && content.is_none() && age.is_some()).then_some(damage);
+
+        // Draw to the window:
+        match content {
+            None if self.settings.retained => {
+                self.retained
+                    .restore(canvas, app_size, bytes_per_pixel, age);
+                self.state.draw_frame(canvas, &frame_info);
+                self.retained
+                    .save(canvas, app_size, bytes_per_pixel, damage.as_deref());
+            }
+            None => self.state.draw_frame(canvas, &frame_info),
+            Some(content) => {
+                // The app draws at the virtual resolution, which is then scaled up to the window
+                self.virtual_buffer.resize(
+                    app_size.width as usize * app_size.height as usize * bytes_per_pixel,
+                    0,
+                );
+                self.state.draw_frame(&mut self.virtual_buffer, &frame_info);
+
+                match (layout, self.viewport.as_ref()) {
+                    (Some(layout), Some(viewport)) => {
+                        layout.copy(&self.virtual_buffer, app_size, canvas, format);
+                        viewport.set_source(0.0, 0.0, layout.source.0, layout.source.1);
+                        viewport.set_destination(self.width as i32, self.height as i32);
+                    }
+                    _ => scale::scale_nearest(
+                        &self.virtual_buffer,
+                        app_size,
+                        canvas,
+                        window_size,
+                        content,
+                        format,
+                    ),
+                }
+            }
+        }
+
+        if self.settings.validate_alpha && format.has_alpha() && !self.invalid_alpha_reported {
+            let drawn: &[u8] = match content {
+                None => canvas,
+                Some(_) => &self.virtual_buffer,
+            };
+            if let Some(index) = color::find_invalid_pixel(drawn) {
+                self.invalid_alpha_reported = true;
+                eprintln!(
+                    "wlib: pixel ({}, {}) has a color larger than its alpha. Colors need to be \
+                     premultiplied, see wlib::color",
+                    index as u32 % app_size.width,
+                    index as u32 / app_size.width,
+                );
+            }
+        }
+
+        let surface = self.role.wl_surface();
+
+        if self.regions_dirty {
+            self.regions_dirty = false;
+            self.settings
+                .apply_regions(surface, compositor, window_size);
+        }
+
+        match damage {
+            Some(damage) => {
+                for rect in damage {
+                    surface.damage_buffer(rect.x, rect.y, rect.width as i32, rect.height as i32);
+                }
+            }
+            // Damage the entire window
+            None => surface.damage_buffer(0, 0, width as i32, height as i32),
+        }
 
         // Request our next frame
-        self.window
-            .wl_surface()
-            .frame(qh, self.window.wl_surface().clone());
+        surface.frame(qh, surface.clone());
 
         // Attach and commit to present.
-        buffer
-            .attach_to(self.window.wl_surface())
-            .expect("buffer attach");
-        self.window.commit();
+        buffer.attach_to(surface).expect("buffer attach");
+        surface.commit();
     }
 
-    fn handle_update(&mut self, request: Option<WLibRequest>) {
-        match request {
-            Some(WLibRequest::CloseAccepted) => self.close_accepted = true,
-            None => {}
+    /// Resizes the buffer, within the limits in `settings`
+    fn request_size(&mut self, size: WindowSize) {
+        let current = WindowSize {
+            width: self.width,
+            height: self.height,
+        };
+        let Some(size) = self
+            .settings
+            .requested_size(size, current, &self.context.window_state)
+        else {
+            return;
+        };
+
+        // The new buffer size is picked up by the draw straight after this
+        self.width = size.width;
+        self.height = size.height;
+
+        let old_size = self.context.window_size;
+        self.context.window_size = self.app_size();
+        self.update_window_geometry();
+        if old_size != self.context.window_size {
+            self.state.resized(old_size, self.context.window_size);
+        }
+    }
+
+    /// The size the app draws at. The virtual resolution if there is one, otherwise the window
+    /// size
+    fn app_size(&self) -> WindowSize {
+        match self.settings.virtual_resolution {
+            Some((size, _)) => size,
+            None => WindowSize {
+                width: self.width,
+                height: self.height,
+            },
+        }
+    }
+
+    /// Where the virtual resolution is drawn in the window, if there is one
+    fn virtual_content_rect(&self) -> Option<Rect> {
+        let (virtual_size, letterbox) = self.settings.virtual_resolution?;
+        Some(letterbox.content_rect(
+            virtual_size,
+            WindowSize {
+                width: self.width,
+                height: self.height,
+            },
+        ))
+    }
+
+    /// The user wants to close the window. It stays open until the app accepts
+    fn close_requested(&mut self) {
+        self.context.close_requested = true;
+        self.context.event_queue.push(Event::CloseRequested);
+        self.state.close_requested();
+    }
+
+    /// Tells the compositor which part of the surface is the window, leaving out the shadows etc
+    /// of client side decorations
+    fn update_window_geometry(&mut self) {
+        // The opaque region of `WLibSettings::with_opaque` follows the window size
+        self.regions_dirty = true;
+
+        let (x, y, width, height) = match self.window_frame.as_mut() {
+            Some(window_frame) if !window_frame.is_hidden() => {
+                window_frame.resize(
+                    NonZeroU32::new(self.width).unwrap_or(NonZeroU32::MIN),
+                    NonZeroU32::new(self.height).unwrap_or(NonZeroU32::MIN),
+                );
+                let (x, y) = window_frame.location();
+                let (width, height) = window_frame.add_borders(self.width, self.height);
+                (x, y, width, height)
+            }
+            _ => (0, 0, self.width, self.height),
+        };
+
+        if let Some(xdg_surface) = self.role.xdg_surface() {
+            xdg_surface.set_window_geometry(x, y, width as i32, height as i32);
         }
     }
 }
 
 delegate_compositor!(WindowManager);
+delegate_subcompositor!(WindowManager);
 delegate_output!(WindowManager);
 delegate_shm!(WindowManager);
 
@@ -790,13 +2817,270 @@ delegate_pointer!(WindowManager);
 
 delegate_xdg_shell!(WindowManager);
 delegate_xdg_window!(WindowManager);
+delegate_xdg_popup!(WindowManager);
 delegate_activation!(WindowManager);
 
 delegate_registry!(WindowManager);
 
+impl Dispatch<WpViewporter, ()> for WindowManager {
+    fn event(
+        _: &mut Self,
+        _: &WpViewporter,
+        _: wp_viewporter::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        // No events
+    }
+}
+
+impl Dispatch<WpViewport, ()> for WindowManager {
+    fn event(
+        _: &mut Self,
+        _: &WpViewport,
+        _: wp_viewport::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        // No events
+    }
+}
+
 impl ProvidesRegistryState for WindowManager {
     fn registry(&mut self) -> &mut RegistryState {
         &mut self.registry_state
     }
     registry_handlers![OutputState, SeatState,];
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_click_counting() {
+        let mut counter = ClickCounter::default();
+        let mut press = |button, time, position| {
+            counter.press(button, time, position, Duration::from_millis(400), 4.0)
+        };
+
+        assert_eq!(press(MouseButton::BtnLeft, 1000, (10.0, 10.0)), 1);
+        assert_eq!(press(MouseButton::BtnLeft, 1200, (11.0, 10.0)), 2);
+        assert_eq!(press(MouseButton::BtnLeft, 1500, (11.0, 12.0)), 3);
+
+        // Too slow
+        assert_eq!(press(MouseButton::BtnLeft, 2000, (11.0, 12.0)), 1);
+        // Too far
+        assert_eq!(press(MouseButton::BtnLeft, 2100, (30.0, 12.0)), 1);
+        // Different button
+        assert_eq!(press(MouseButton::BtnRight, 2200, (30.0, 12.0)), 1);
+        assert_eq!(press(MouseButton::BtnRight, 2300, (30.0, 12.0)), 2);
+    }
+
+    #[test]
+    fn test_black() {
+        let formats = [
+            PixelFormat::Argb8888,
+            PixelFormat::Xrgb8888,
+            PixelFormat::Abgr8888,
+            PixelFormat::Xbgr8888,
+            PixelFormat::Rgb565,
+            PixelFormat::Xrgb2101010,
+        ];
+        for format in formats {
+            let black = format.black();
+            let pixel = u32::from_le_bytes(black);
+            // Red, green and blue
+            let channels = match format {
+                PixelFormat::Argb8888 | PixelFormat::Xrgb8888 => {
+                    [pixel >> 16 & 0xFF, pixel >> 8 & 0xFF, pixel & 0xFF]
+                }
+                PixelFormat::Abgr8888 | PixelFormat::Xbgr8888 => {
+                    [pixel & 0xFF, pixel >> 8 & 0xFF, pixel >> 16 & 0xFF]
+                }
+                PixelFormat::Rgb565 => {
+                    let pixel = u16::from_le_bytes([black[0], black[1]]) as u32;
+                    [pixel >> 11, pixel >> 5 & 0x3F, pixel & 0x1F]
+                }
+                PixelFormat::Xrgb2101010 => {
+                    [pixel >> 20 & 0x3FF, pixel >> 10 & 0x3FF, pixel & 0x3FF]
+                }
+            };
+            assert_eq!(channels, [0; 3], "{format:?}");
+            if format.has_alpha() {
+                assert_eq!(pixel >> 24, 0xFF, "{format:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_pixel_format_fallback() {
+        let supported = [
+            wl_shm::Format::Argb8888,
+            wl_shm::Format::Xrgb8888,
+            wl_shm::Format::Rgb565,
+        ];
+
+        assert_eq!(
+            PixelFormat::Rgb565.or_supported(&supported),
+            PixelFormat::Rgb565
+        );
+        assert_eq!(
+            PixelFormat::Abgr8888.or_supported(&supported),
+            PixelFormat::Argb8888
+        );
+        assert_eq!(
+            PixelFormat::Xrgb2101010.or_supported(&supported),
+            PixelFormat::Xrgb8888
+        );
+    }
+
+    #[test]
+    fn test_press_serial() {
+        let press = PointerEventKind::Press {
+            time: 0,
+            button: 0x110,
+            serial: 7,
+        };
+        let release = PointerEventKind::Release {
+            time: 10,
+            button: 0x110,
+            serial: 8,
+        };
+        assert_eq!(press_serial(&press), Some(7));
+        // Keeps the press serial for a move started after the button went back up
+        assert_eq!(press_serial(&release), None);
+    }
+
+    #[test]
+    fn test_choose_size() {
+        let size = |width, height| WindowSize { width, height };
+        let current = size(300, 200);
+        let normal = WindowState::default();
+
+        let settings = WLibSettings::new();
+        assert_eq!(
+            settings.choose_size((None, None), current, &normal),
+            current
+        );
+        assert_eq!(
+            settings.choose_size((Some(800), None), current, &normal),
+            size(800, 200)
+        );
+
+        let settings = WLibSettings::new()
+            .with_min_size(size(100, 100))
+            .with_max_size(size(1000, 500));
+        assert_eq!(
+            settings.choose_size((Some(50), Some(2000)), current, &normal),
+            size(100, 500)
+        );
+
+        let settings = WLibSettings::new().with_aspect_ratio(16, 9);
+        assert_eq!(
+            settings.choose_size((Some(1920), Some(1200)), current, &normal),
+            size(1920, 1080)
+        );
+        assert_eq!(
+            settings.choose_size((Some(1000), Some(1080)), current, &normal),
+            size(1000, 562)
+        );
+
+        let settings = WLibSettings::new().with_static_size(size(400, 400));
+        assert_eq!(
+            settings.choose_size((Some(1920), Some(1080)), current, &normal),
+            size(400, 400)
+        );
+
+        // The aspect ratio can't shrink below the minimum size
+        let settings = WLibSettings::new()
+            .with_min_size(size(400, 300))
+            .with_aspect_ratio(1, 1);
+        assert_eq!(
+            settings.choose_size((Some(1000), Some(100)), current, &normal),
+            size(400, 300)
+        );
+
+        // Maximized, fullscreen and tiled windows take the configured size as is
+        let settings = WLibSettings::new()
+            .with_max_size(size(1000, 500))
+            .with_aspect_ratio(16, 9);
+        let maximized = WindowState {
+            maximized: true,
+            ..WindowState::default()
+        };
+        let fullscreen = WindowState {
+            fullscreen: true,
+            ..WindowState::default()
+        };
+        let tiled = WindowState {
+            tiled_left: true,
+            ..WindowState::default()
+        };
+        for state in [maximized, fullscreen, tiled] {
+            assert_eq!(
+                settings.choose_size((Some(1920), Some(1200)), current, &state),
+                size(1920, 1200)
+            );
+        }
+    }
+
+    #[test]
+    fn test_popup_size() {
+        let size = |width, height| WindowSize { width, height };
+        assert_eq!(PopupSettings::new(size(0, 0)).size, size(1, 1));
+        assert_eq!(
+            PopupSettings::new(size(u32::MAX, 200)).size,
+            size(MAX_REQUESTED_SIZE, 200)
+        );
+    }
+
+    #[test]
+    fn test_subsurface_size() {
+        let size = |width, height| WindowSize { width, height };
+        assert_eq!(SubsurfaceSettings::new(size(0, 50)).size, size(1, 50));
+        assert_eq!(
+            SubsurfaceSettings::new(size(100, u32::MAX)).size,
+            size(100, MAX_REQUESTED_SIZE)
+        );
+    }
+
+    #[test]
+    fn test_requested_size() {
+        let size = |width, height| WindowSize { width, height };
+        let current = size(300, 200);
+        let normal = WindowState::default();
+
+        let settings = WLibSettings::new();
+        assert_eq!(
+            settings.requested_size(size(0, 100), current, &normal),
+            None
+        );
+        assert_eq!(
+            settings.requested_size(size(100, 0), current, &normal),
+            None
+        );
+        assert_eq!(
+            settings.requested_size(size(u32::MAX, 100), current, &normal),
+            Some(size(MAX_REQUESTED_SIZE, 100))
+        );
+
+        let settings = WLibSettings::new().with_max_size(size(1000, 500));
+        assert_eq!(
+            settings.requested_size(size(4000, 400), current, &normal),
+            Some(size(1000, 400))
+        );
+
+        // Maximized windows have to stay the configured size
+        let maximized = WindowState {
+            maximized: true,
+            ..WindowState::default()
+        };
+        assert_eq!(
+            settings.requested_size(size(400, 400), current, &maximized),
+            None
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use wlib::{WindowAble, keys};
+use wlib::{WindowAble, keys::Key};
 
 struct State {
     pos_x: f64,
@@ -18,7 +18,7 @@ impl WindowAble for State {
 
                 // Draw a cross across the whole screen, intersecting at the States position
                 if x == self.pos_x as u32 || y == self.pos_y as u32 {
-                    buffer[index + 0] = 0;
+                    buffer[index] = 0;
                     buffer[index + 1] = 0;
                     buffer[index + 2] = 0;
                     buffer[index + 3] = 0;
@@ -34,7 +34,7 @@ impl WindowAble for State {
                 let b = u32::min(((width - x) * 0xFF) / width, (y * 0xFF) / height);
                 let a = 255;
 
-                buffer[index + 0] = b as u8;
+                buffer[index] = b as u8;
                 buffer[index + 1] = g as u8;
                 buffer[index + 2] = r as u8;
                 buffer[index + 3] = a as u8;
@@ -42,41 +42,36 @@ impl WindowAble for State {
         }
     }
 
-    fn update(&mut self, context: wlib::Context) -> Option<wlib::WLibRequest> {
+    fn update_requests(&mut self, context: wlib::Context, requests: &mut Vec<wlib::WLibRequest>) {
+        if context.close_requested && !self.close_requested {
+            requests.push(wlib::WLibRequest::SetTitle("Press Y to close".to_string()));
+        }
         self.close_requested = context.close_requested;
 
         // Only close the window if the user accepts the close action
-        if self.close_requested && context.pressed_keys.contains_key(&wlib::keys::KEY_Y) {
-            return Some(wlib::WLibRequest::CloseAccepted);
+        if self.close_requested && context.is_down(Key::KeyY) {
+            requests.push(wlib::WLibRequest::CloseAccepted);
         }
 
         // If the m key is pressed, toggle the mouse mode.
-        // This is done via the event queue because we specfically want to activiate on key press
-        if context.event_queue.iter().any(|event| {
-            matches!(
-                event,
-                wlib::Event::KeyPress(wlib::keyboard::KeyEvent {
-                    raw_code: keys::KEY_M,
-                    ..
-                })
-            )
-        }) {
+        // was_pressed is used because we specfically want to activiate on key press
+        if context.was_pressed(Key::KeyM) {
             self.is_mouse_mode = !self.is_mouse_mode;
         }
 
         let speed = 200.0 * context.delta_time.as_secs_f64();
 
         // Handle keyboard input
-        if context.pressed_keys.contains_key(&keys::KEY_W) && self.pos_y > 0.0 {
+        if context.is_down(Key::KeyW) && self.pos_y > 0.0 {
             self.pos_y -= speed;
         }
-        if context.pressed_keys.contains_key(&keys::KEY_S) {
+        if context.is_down(Key::KeyS) {
             self.pos_y += speed;
         }
-        if context.pressed_keys.contains_key(&keys::KEY_A) && self.pos_x > 0.0 {
+        if context.is_down(Key::KeyA) && self.pos_x > 0.0 {
             self.pos_x -= speed;
         }
-        if context.pressed_keys.contains_key(&keys::KEY_D) {
+        if context.is_down(Key::KeyD) {
             self.pos_x += speed;
         }
 
@@ -84,8 +79,6 @@ impl WindowAble for State {
         if self.is_mouse_mode {
             (self.pos_x, self.pos_y) = context.mouse_state.position;
         }
-
-        None
     }
 }
 
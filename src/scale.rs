@@ -0,0 +1,341 @@
+//! Drawing at a fixed low resolution and scaling it up to fill the window.
+//! See `WLibSettings::with_virtual_resolution`
+
+use crate::{PixelFormat, Rect, WindowSize};
+
+/// How the virtual resolution is fit into a window that is a different shape
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Letterbox {
+    /// Fill the whole window, stretching the image if the window is a different shape
+    Stretch,
+    /// Scale as large as fits while keeping the shape, with black bars filling the rest
+    #[default]
+    KeepAspect,
+    /// Like `KeepAspect`, but only scale by whole numbers so every virtual pixel is the same
+    /// size. Leaves larger bars
+    IntegerScale,
+}
+
+/// What does the scaling
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Scaler {
+    /// wlib scales the image into the window's buffer, using nearest neighbour
+    #[default]
+    Software,
+    /// The compositor scales the image, using wp_viewporter. Cheaper, since only the virtual
+    /// resolution is copied to the compositor. The compositor picks the filtering, so it may look
+    /// blurry. Falls back to `Software` if the compositor doesn't support it
+    Compositor,
+}
+
+impl Letterbox {
+    /// Where a `virtual_size` image goes inside a `window_size` window
+    pub(crate) fn content_rect(self, virtual_size: WindowSize, window_size: WindowSize) -> Rect {
+        let scale_x = window_size.width as f64 / virtual_size.width.max(1) as f64;
+        let scale_y = window_size.height as f64 / virtual_size.height.max(1) as f64;
+
+        let scale = match self {
+            Letterbox::Stretch => {
+                return Rect {
+                    x: 0,
+                    y: 0,
+                    width: window_size.width,
+                    height: window_size.height,
+                };
+            }
+            Letterbox::KeepAspect => scale_x.min(scale_y),
+            // Shrink like `KeepAspect` if the window is smaller than the virtual resolution
+            Letterbox::IntegerScale if scale_x.min(scale_y) < 1.0 => scale_x.min(scale_y),
+            Letterbox::IntegerScale => scale_x.min(scale_y).floor(),
+        };
+
+        let width = ((virtual_size.width as f64 * scale).round() as u32).max(1);
+        let height = ((virtual_size.height as f64 * scale).round() as u32).max(1);
+        Rect {
+            x: (window_size.width.saturating_sub(width) / 2) as i32,
+            y: (window_size.height.saturating_sub(height) / 2) as i32,
+            width,
+            height,
+        }
+    }
+}
+
+/// Converts a position in the window to a position in the virtual resolution. Positions over the
+/// black bars end up outside of it
+pub(crate) fn to_virtual(
+    position: (f64, f64),
+    content: Rect,
+    virtual_size: WindowSize,
+) -> (f64, f64) {
+    (
+        (position.0 - content.x as f64) * virtual_size.width as f64 / content.width as f64,
+        (position.1 - content.y as f64) * virtual_size.height as f64 / content.height as f64,
+    )
+}
+
+/// The inverse of `to_virtual`. Converts a position in the virtual resolution to a position in
+/// the window
+pub(crate) fn from_virtual(
+    position: (f64, f64),
+    content: Rect,
+    virtual_size: WindowSize,
+) -> (f64, f64) {
+    (
+        position.0 * content.width as f64 / virtual_size.width as f64 + content.x as f64,
+        position.1 * content.height as f64 / virtual_size.height as f64 + content.y as f64,
+    )
+}
+
+/// Converts a rectangle in the virtual resolution to the window pixels it covers. Never empty
+pub(crate) fn rect_from_virtual(rect: Rect, content: Rect, virtual_size: WindowSize) -> Rect {
+    let (left, top) = from_virtual((rect.x as f64, rect.y as f64), content, virtual_size);
+    let (right, bottom) = from_virtual(
+        (
+            rect.x as f64 + rect.width as f64,
+            rect.y as f64 + rect.height as f64,
+        ),
+        content,
+        virtual_size,
+    );
+    let (left, top) = (left.floor(), top.floor());
+    Rect {
+        x: left as i32,
+        y: top as i32,
+        width: ((right.ceil() - left) as u32).max(1),
+        height: ((bottom.ceil() - top) as u32).max(1),
+    }
+}
+
+/// Fills the bars around the image with `bar`, one pixel of black
+fn fill_bar(pixels: &mut [u8], bar: &[u8]) {
+    for pixel in pixels.chunks_exact_mut(bar.len()) {
+        pixel.copy_from_slice(bar);
+    }
+}
+
+/// Scales `source` up into the `content` area of `target`, and fills the rest with black
+pub(crate) fn scale_nearest(
+    source: &[u8],
+    source_size: WindowSize,
+    target: &mut [u8],
+    target_size: WindowSize,
+    content: Rect,
+    format: PixelFormat,
+) {
+    let bytes_per_pixel = format.bytes_per_pixel() as usize;
+    let black = format.black();
+    let bar = &black[..bytes_per_pixel];
+
+    // Which source column each target column in the content area reads from
+    let columns: Vec<usize> = (0..content.width)
+        .map(|x| (x as u64 * source_size.width as u64 / content.width as u64) as usize)
+        .collect();
+
+    let target_stride = target_size.width as usize * bytes_per_pixel;
+    let source_stride = source_size.width as usize * bytes_per_pixel;
+    for (y, row) in target.chunks_exact_mut(target_stride).enumerate() {
+        let content_y = y as i64 - content.y as i64;
+        if content_y < 0 || content_y >= content.height as i64 {
+            fill_bar(row, bar);
+            continue;
+        }
+
+        let source_y =
+            (content_y as u64 * source_size.height as u64 / content.height as u64) as usize;
+        let source_row = &source[source_y * source_stride..][..source_stride];
+        for (x, pixel) in row.chunks_exact_mut(bytes_per_pixel).enumerate() {
+            let content_x = x as i64 - content.x as i64;
+            if content_x < 0 || content_x >= content.width as i64 {
+                pixel.copy_from_slice(bar);
+                continue;
+            }
+            let source_x = columns[content_x as usize];
+            pixel.copy_from_slice(&source_row[source_x * bytes_per_pixel..][..bytes_per_pixel]);
+        }
+    }
+}
+
+/// How to lay out a buffer for the compositor to scale up, so it ends up looking like
+/// `scale_nearest` would have. The buffer is the virtual resolution plus black bars, in virtual
+/// pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ViewportLayout {
+    pub buffer_size: WindowSize,
+    /// Where the image goes in the buffer
+    pub offset: (u32, u32),
+    /// The part of the buffer stretched over the window. Can be a fraction of a pixel smaller
+    /// than the buffer so the scale comes out exact
+    pub source: (f64, f64),
+}
+
+impl ViewportLayout {
+    pub fn new(content: Rect, virtual_size: WindowSize, window_size: WindowSize) -> Self {
+        let scale_x = content.width as f64 / virtual_size.width as f64;
+        let scale_y = content.height as f64 / virtual_size.height as f64;
+        let source = (
+            window_size.width as f64 / scale_x,
+            window_size.height as f64 / scale_y,
+        );
+
+        Self {
+            buffer_size: WindowSize {
+                width: (source.0.ceil() as u32).max(virtual_size.width),
+                height: (source.1.ceil() as u32).max(virtual_size.height),
+            },
+            offset: (
+                (content.x as f64 / scale_x).round() as u32,
+                (content.y as f64 / scale_y).round() as u32,
+            ),
+            source,
+        }
+    }
+
+    /// Copies `source` into the middle of `target`, and fills the rest with black
+    pub fn copy(
+        &self,
+        source: &[u8],
+        source_size: WindowSize,
+        target: &mut [u8],
+        format: PixelFormat,
+    ) {
+        let bytes_per_pixel = format.bytes_per_pixel() as usize;
+        let black = format.black();
+        let bar = &black[..bytes_per_pixel];
+        let target_stride = self.buffer_size.width as usize * bytes_per_pixel;
+        let source_stride = source_size.width as usize * bytes_per_pixel;
+        let buffer_width = self.buffer_size.width as usize;
+        let offset_x = (self.offset.0 as usize).min(buffer_width);
+        let offset_y = self.offset.1 as usize;
+        let copy_width = (source_size.width as usize).min(buffer_width - offset_x);
+
+        for (y, row) in target.chunks_exact_mut(target_stride).enumerate() {
+            let Some(source_row) = y
+                .checked_sub(offset_y)
+                .filter(|source_y| *source_y < source_size.height as usize)
+                .map(|source_y| &source[source_y * source_stride..][..source_stride])
+            else {
+                fill_bar(row, bar);
+                continue;
+            };
+
+            let (left, rest) = row.split_at_mut(offset_x * bytes_per_pixel);
+            let (middle, right) = rest.split_at_mut(copy_width * bytes_per_pixel);
+            fill_bar(left, bar);
+            middle.copy_from_slice(&source_row[..copy_width * bytes_per_pixel]);
+            fill_bar(right, bar);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(width: u32, height: u32) -> WindowSize {
+        WindowSize { width, height }
+    }
+
+    fn rect(x: i32, y: i32, width: u32, height: u32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn test_content_rect() {
+        let virtual_size = size(320, 200);
+
+        assert_eq!(
+            Letterbox::Stretch.content_rect(virtual_size, size(1000, 700)),
+            rect(0, 0, 1000, 700)
+        );
+        assert_eq!(
+            Letterbox::KeepAspect.content_rect(virtual_size, size(1000, 700)),
+            rect(0, 37, 1000, 625)
+        );
+        assert_eq!(
+            Letterbox::IntegerScale.content_rect(virtual_size, size(1000, 700)),
+            rect(20, 50, 960, 600)
+        );
+        // Too small for even 1x
+        assert_eq!(
+            Letterbox::IntegerScale.content_rect(virtual_size, size(160, 200)),
+            rect(0, 50, 160, 100)
+        );
+
+        let content = rect(20, 50, 960, 600);
+        assert_eq!(to_virtual((20.0, 50.0), content, virtual_size), (0.0, 0.0));
+        assert_eq!(
+            to_virtual((980.0, 650.0), content, virtual_size),
+            (320.0, 200.0)
+        );
+        assert_eq!(to_virtual((0.0, 0.0), content, virtual_size).0, -20.0 / 3.0);
+
+        assert_eq!(
+            from_virtual((0.0, 0.0), content, virtual_size),
+            (20.0, 50.0)
+        );
+        assert_eq!(
+            from_virtual((320.0, 200.0), content, virtual_size),
+            (980.0, 650.0)
+        );
+        let position = (123.5, 45.25);
+        assert_eq!(
+            to_virtual(
+                from_virtual(position, content, virtual_size),
+                content,
+                virtual_size
+            ),
+            position
+        );
+
+        assert_eq!(
+            rect_from_virtual(rect(10, 20, 1, 1), content, virtual_size),
+            rect(50, 110, 3, 3)
+        );
+        assert_eq!(
+            rect_from_virtual(rect(0, 0, 0, 0), content, virtual_size),
+            rect(20, 50, 1, 1)
+        );
+    }
+
+    #[test]
+    fn test_scaling() {
+        // 2x1 red and green image
+        let red = [0, 0, 0xFF, 0xFF];
+        let green = [0, 0xFF, 0, 0xFF];
+        let source = [red, green].concat();
+        let source_size = size(2, 1);
+        let format = PixelFormat::Argb8888;
+        let bar = format.black();
+
+        // Scaled 2x into a 4x4 window, with a bar above and below
+        let content = Letterbox::KeepAspect.content_rect(source_size, size(4, 4));
+        let mut target = vec![0; 4 * 4 * 4];
+        scale_nearest(
+            &source,
+            source_size,
+            &mut target,
+            size(4, 4),
+            content,
+            format,
+        );
+        let rows: Vec<&[u8]> = target.chunks(16).collect();
+        assert_eq!(rows[0], [bar; 4].concat());
+        assert_eq!(rows[1], [red, red, green, green].concat());
+        assert_eq!(rows[2], [red, red, green, green].concat());
+        assert_eq!(rows[3], [bar; 4].concat());
+
+        // The compositor gets the image with the whole bar above, since it can't be split into
+        // half pixels
+        let layout = ViewportLayout::new(content, source_size, size(4, 4));
+        assert_eq!(layout.buffer_size, size(2, 2));
+        assert_eq!(layout.source, (2.0, 2.0));
+        let mut target = vec![0; 2 * 2 * 4];
+        layout.copy(&source, source_size, &mut target, format);
+        assert_eq!(target, [bar, bar, red, green].concat());
+    }
+}